@@ -1,7 +1,9 @@
 use super::{
     AddGatewayReq, AddGatewayRes, PubkeyReq, PubkeyRes, RegionReq, RegionRes, RouterReq, RouterRes,
 };
-use crate::{packet_router, region_watcher, Error, Keypair, PublicKey, Result, Settings};
+use crate::{
+    packet_router, region_watcher, watchdog::Heartbeat, Error, Keypair, PublicKey, Result, Settings,
+};
 use futures::TryFutureExt;
 use helium_crypto::Sign;
 use helium_proto::services::local::{Api, Server};
@@ -18,6 +20,7 @@ pub struct LocalServer {
     keypair: Arc<Keypair>,
     onboarding_key: PublicKey,
     listen_addr: SocketAddr,
+    heartbeat: Heartbeat,
 }
 
 impl LocalServer {
@@ -32,18 +35,31 @@ impl LocalServer {
             listen_addr: (&settings.api).try_into()?,
             region_watch,
             packet_router,
+            heartbeat: Heartbeat::default(),
         })
     }
 
-    pub async fn run(self, shutdown: &triggered::Listener) -> Result {
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    pub async fn run(mut self, shutdown: &triggered::Listener) -> Result {
         let listen_addr = self.listen_addr;
         tracing::Span::current().record("listen", &listen_addr.to_string());
         info!(listen = %listen_addr, "starting");
-        TransportServer::builder()
+        let mut heartbeat = std::mem::take(&mut self.heartbeat);
+        let server = TransportServer::builder()
             .add_service(Server::new(self))
             .serve_with_shutdown(listen_addr, shutdown.clone())
-            .map_err(Error::from)
-            .await
+            .map_err(Error::from);
+        tokio::pin!(server);
+        loop {
+            tokio::select! {
+                result = &mut server => return result,
+                _ = heartbeat.tick() => (),
+            }
+        }
     }
 }
 
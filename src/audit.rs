@@ -0,0 +1,338 @@
+//! This module provides an audit log of forwarded uplinks, transmitted
+//! downlinks and transmitted PoC beacons.
+//!
+//! Audit records are written as JSON lines to a daily rolling file in the
+//! configured audit directory. The record schema is meant to be stable so
+//! records can be processed by external tools, for example for dispute
+//! resolution. Records are written through a non-blocking writer to keep file
+//! I/O off the packet paths.
+use crate::{packet, settings, PacketDown, PacketUp, Result, Settings};
+use semtech_udp::pull_resp;
+use serde::Serialize;
+use std::{io::Write, path::Path, time::Duration};
+use tracing::warn;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+
+pub const AUDIT_FILE_PREFIX: &str = "audit.log";
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog(Option<NonBlocking>);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditRecord {
+    Uplink {
+        /// Unix timestamp in milliseconds the record was created
+        timestamp: u64,
+        /// Concentrator timestamp of the received packet
+        tmst: u32,
+        /// Device address of the uplink as hex, if it is a data frame
+        devaddr: Option<String>,
+        /// Frequency in Hz
+        frequency: u64,
+        datarate: String,
+        /// Time on air in microseconds, if the datarate is known and in the
+        /// LoRaWAN range
+        airtime: Option<u64>,
+        rssi: i32,
+        snr: f32,
+        payload_size: usize,
+        /// Base64 encoded sha256 hash of the payload
+        payload_hash: String,
+        /// Packet router the uplink was delivered to
+        router: String,
+        /// Time in milliseconds the uplink was held before delivery
+        hold_time: u64,
+    },
+    Downlink {
+        /// Unix timestamp in milliseconds the record was created
+        timestamp: u64,
+        /// Receive window the downlink was transmitted in (rx1 or rx2)
+        window: String,
+        /// Device address of the downlink as hex, if it is a data frame
+        devaddr: Option<String>,
+        /// Frequency in Hz
+        frequency: u64,
+        datarate: String,
+        /// Time on air in microseconds, if the datarate is in the LoRaWAN
+        /// range
+        airtime: Option<u64>,
+        /// Transmit power in dBm
+        tx_power: u64,
+        payload_size: usize,
+    },
+    Beacon {
+        /// Unix timestamp in milliseconds the record was created
+        timestamp: u64,
+        /// Concentrator timestamp of the transmission, if reported
+        tmst: Option<u32>,
+        /// Base64 encoded id of the beacon
+        beacon_id: String,
+        /// Frequency in Hz
+        frequency: u64,
+        datarate: String,
+        /// Time on air in microseconds, if the datarate is in the LoRaWAN
+        /// range
+        airtime: Option<u64>,
+        /// Transmit power in dBm as used by the packet forwarder
+        tx_power: i32,
+        payload_size: usize,
+    },
+}
+
+impl AuditLog {
+    /// Constructs an audit log as configured in the given settings. The
+    /// returned guard flushes outstanding records when dropped and must be
+    /// held for as long as the audit log is in use. If no audit directory is
+    /// configured a disabled audit log is returned.
+    pub fn new(settings: &Settings) -> Result<(Self, Option<WorkerGuard>)> {
+        let Some(dir) = settings.log.audit.as_ref() else {
+            return Ok((Self::default(), None));
+        };
+        let (audit, guard) = Self::with_dir(dir)?;
+        Ok((audit, Some(guard)))
+    }
+
+    /// Constructs an audit log which writes to daily rolling files in the
+    /// given directory. Fails if the directory can not be created or written.
+    pub fn with_dir(dir: &Path) -> Result<(Self, WorkerGuard)> {
+        settings::init_writable_dir("audit", dir)?;
+        let (writer, guard) = tracing_appender::non_blocking(tracing_appender::rolling::daily(
+            dir,
+            AUDIT_FILE_PREFIX,
+        ));
+        Ok((Self(Some(writer)), guard))
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        let Some(mut writer) = self.0.clone() else {
+            return;
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                warn!(%err, "failed to encode audit record");
+                return;
+            }
+        };
+        line.push(b'\n');
+        if let Err(err) = writer.write_all(&line) {
+            warn!(%err, "failed to write audit record");
+        }
+    }
+}
+
+impl AuditRecord {
+    pub fn uplink(uplink: &PacketUp, router: &http::Uri, hold_time: Duration) -> Self {
+        use crate::Base64;
+        let datarate = packet::datarate::from_proto(uplink.datarate()).ok();
+        Self::Uplink {
+            timestamp: now_millis(),
+            tmst: uplink.tmst(),
+            devaddr: uplink.dev_addr().map(fmt_dev_addr),
+            frequency: uplink.frequency as u64,
+            datarate: datarate
+                .as_ref()
+                .map(|datarate| datarate.to_string())
+                .unwrap_or_else(|| format!("{:?}", uplink.datarate())),
+            airtime: datarate
+                .and_then(|datarate| {
+                    // Uplinks are reported without their coding rate
+                    packet::airtime(
+                        &datarate,
+                        &packet::AirtimeParams::default(),
+                        uplink.payload().len(),
+                    )
+                })
+                .map(|airtime| airtime.as_micros() as u64),
+            rssi: uplink.rssi,
+            snr: uplink.snr,
+            payload_size: uplink.payload().len(),
+            payload_hash: uplink.hash().to_b64(),
+            router: router.to_string(),
+            hold_time: hold_time.as_millis() as u64,
+        }
+    }
+
+    pub fn downlink(window: &str, txpk: &pull_resp::TxPk, downlink: &PacketDown) -> Self {
+        let payload_size = downlink.payload().len();
+        Self::Downlink {
+            timestamp: now_millis(),
+            window: window.to_string(),
+            devaddr: downlink.dev_addr().map(fmt_dev_addr),
+            frequency: packet::to_hz(txpk.freq),
+            datarate: txpk.datr.to_string(),
+            airtime: packet::airtime(
+                &txpk.datr,
+                &packet::AirtimeParams::from_txpk(txpk),
+                payload_size,
+            )
+            .map(|airtime| airtime.as_micros() as u64),
+            tx_power: txpk.powe,
+            payload_size,
+        }
+    }
+
+    pub fn beacon(
+        beacon: &beacon::Beacon,
+        txpk: &pull_resp::TxPk,
+        tx_power: i32,
+        tmst: Option<u32>,
+    ) -> Self {
+        let payload_size = beacon.data.len() + PacketUp::header_size();
+        Self::Beacon {
+            timestamp: now_millis(),
+            tmst,
+            beacon_id: beacon.beacon_id(),
+            frequency: packet::to_hz(txpk.freq),
+            datarate: txpk.datr.to_string(),
+            airtime: packet::airtime(
+                &txpk.datr,
+                &packet::AirtimeParams::from_txpk(txpk),
+                payload_size,
+            )
+            .map(|airtime| airtime.as_micros() as u64),
+            tx_power,
+            payload_size,
+        }
+    }
+}
+
+fn fmt_dev_addr(dev_addr: u32) -> String {
+    format!("{dev_addr:08x}")
+}
+
+fn now_millis() -> u64 {
+    (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as u64
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{AuditLog, AuditRecord};
+    use crate::{packet::test::DATA_FRAME, PacketDown, PacketUp};
+    use helium_proto::services::router::{PacketRouterPacketDownV1, PacketRouterPacketUpV1};
+    use semtech_udp::{
+        pull_resp::{self, PhyData, Time},
+        Bandwidth, CodingRate, DataRate, Modulation, SpreadingFactor,
+    };
+    use serde_json::json;
+    use std::{fs, path::Path, time::Duration};
+
+    /// Reads all records written to the audit files in the given directory
+    pub(crate) fn read_records(dir: &Path) -> Vec<serde_json::Value> {
+        let mut records = vec![];
+        for entry in fs::read_dir(dir).expect("audit dir") {
+            let content =
+                fs::read_to_string(entry.expect("audit file").path()).expect("audit file");
+            for line in content.lines() {
+                records.push(serde_json::from_str::<serde_json::Value>(line).expect("json line"));
+            }
+        }
+        records
+    }
+
+    #[test]
+    fn test_audit_record_schema() {
+        let record = AuditRecord::Downlink {
+            timestamp: 1693560000000,
+            window: "rx1".to_string(),
+            devaddr: Some("01020304".to_string()),
+            frequency: 923_300_000,
+            datarate: "SF12BW500".to_string(),
+            airtime: Some(288_768),
+            tx_power: 27,
+            payload_size: 17,
+        };
+        assert_eq!(
+            json!({
+                "type": "downlink",
+                "timestamp": 1693560000000u64,
+                "window": "rx1",
+                "devaddr": "01020304",
+                "frequency": 923_300_000,
+                "datarate": "SF12BW500",
+                "airtime": 288_768,
+                "tx_power": 27,
+                "payload_size": 17,
+            }),
+            serde_json::to_value(record).expect("audit record json")
+        );
+    }
+
+    #[test]
+    fn test_audit_dir_unusable() {
+        let base = std::env::temp_dir().join(format!("audit_dir_{}", std::process::id()));
+        fs::create_dir_all(&base).expect("base dir");
+        let file = base.join("file");
+        fs::write(&file, b"").expect("file");
+        assert!(AuditLog::with_dir(&file.join("audit")).is_err());
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let dir = std::env::temp_dir().join(format!("audit_log_{}", std::process::id()));
+        let (audit, guard) = AuditLog::with_dir(&dir).expect("audit log");
+
+        let uplink = PacketUp::from(PacketRouterPacketUpV1 {
+            payload: DATA_FRAME.to_vec(),
+            frequency: 904_300_000,
+            datarate: helium_proto::DataRate::Sf10bw125 as i32,
+            ..Default::default()
+        });
+        let router: http::Uri = "http://127.0.0.1:8080".parse().expect("uri");
+        audit.record(AuditRecord::uplink(
+            &uplink,
+            &router,
+            Duration::from_millis(100),
+        ));
+
+        let downlink = PacketDown::from(PacketRouterPacketDownV1 {
+            payload: DATA_FRAME.to_vec(),
+            ..Default::default()
+        });
+        let txpk = pull_resp::TxPk {
+            time: Time::immediate(),
+            ipol: true,
+            modu: Modulation::LORA,
+            codr: CodingRate::_4_5,
+            datr: DataRate::new(SpreadingFactor::SF10, Bandwidth::BW500),
+            freq: 923.3,
+            data: PhyData::new(DATA_FRAME.to_vec()),
+            powe: 27,
+            rfch: 0,
+            fdev: None,
+            prea: None,
+            ncrc: None,
+        };
+        audit.record(AuditRecord::downlink("rx1", &txpk, &downlink));
+
+        // Dropping the guard flushes the outstanding records
+        drop(audit);
+        drop(guard);
+
+        let records = read_records(&dir);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(2, records.len());
+        assert_eq!("uplink", records[0]["type"]);
+        assert_eq!("01020304", records[0]["devaddr"]);
+        assert_eq!(904_300_000, records[0]["frequency"]);
+        assert_eq!(
+            crate::packet::airtime(
+                &DataRate::new(SpreadingFactor::SF10, Bandwidth::BW125),
+                &crate::packet::AirtimeParams::default(),
+                DATA_FRAME.len()
+            )
+            .expect("airtime")
+            .as_micros() as u64,
+            records[0]["airtime"]
+        );
+        assert_eq!(100, records[0]["hold_time"]);
+        assert_eq!("downlink", records[1]["type"]);
+        assert_eq!("rx1", records[1]["window"]);
+        assert_eq!("01020304", records[1]["devaddr"]);
+        assert_eq!(923_300_000, records[1]["frequency"]);
+        assert_eq!(DATA_FRAME.len(), records[1]["payload_size"]);
+    }
+}
@@ -5,7 +5,9 @@ use crate::{
     region_watcher,
     service::{entropy::EntropyService, poc::PocIotService, Reconnect},
     settings::Settings,
-    sync, Base64, DecodeError, PacketUp, PublicKey, RegionParams, Result,
+    sync,
+    watchdog::Heartbeat,
+    Base64, DecodeError, PacketUp, PublicKey, RegionParams, Result,
 };
 use futures::TryFutureExt;
 use helium_proto::services::poc_lora::{self, lora_stream_response_v1};
@@ -55,6 +57,8 @@ pub struct Beaconer {
     /// Use for channel plan and FR parameters
     region_params: Arc<RegionParams>,
     entropy_uri: Uri,
+    /// Watchdog heartbeat
+    heartbeat: Heartbeat,
 }
 
 impl Beaconer {
@@ -87,9 +91,15 @@ impl Beaconer {
             entropy_uri,
             disabled,
             reconnect,
+            heartbeat: Heartbeat::default(),
         }
     }
 
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
     pub async fn run(&mut self, shutdown: &triggered::Listener) -> Result {
         info!(
             beacon_interval = self.interval.whole_seconds(),
@@ -106,6 +116,7 @@ impl Beaconer {
                     info!("shutting down");
                     return Ok(())
                 },
+                _ = self.heartbeat.tick() => (),
                 _ = tokio::time::sleep_until(next_beacon_instant.into_inner().into()) => {
                     // Check if beaconing is enabled and we have valid region params
                     if !self.disabled && self.region_params.check_valid().is_ok() {
@@ -1,10 +1,10 @@
 use crate::{
+    animal_name,
     api::LocalClient,
     cmd::*,
     settings::{self, Settings},
     Result,
 };
-use angry_purple_tiger::AnimalName;
 
 use serde_json::json;
 use std::collections::HashMap;
@@ -70,12 +70,7 @@ impl InfoKey {
                 json!(onboarding_key)
             }
             Self::Name => {
-                let name = public_key
-                    .to_string()
-                    .parse::<AnimalName>()
-                    .unwrap()
-                    .to_string();
-                json!(name)
+                json!(animal_name(&public_key))
             }
             Self::Region => {
                 let region = client.region().await?;
@@ -1,8 +1,13 @@
 use crate::{
-    beaconer, packet, packet_router, region_watcher, sync, DecodeError, Error, PacketDown,
-    PacketUp, PublicKey, RegionParams, Result, Settings,
+    audit::{AuditLog, AuditRecord},
+    beaconer,
+    packet::{self, FrameType},
+    packet_router, region_watcher, sync,
+    watchdog::Heartbeat,
+    DecodeError, Error, PacketDown, PacketUp, PublicKey, RegionParams, Result, Settings,
 };
 use beacon::Beacon;
+use futures::future::BoxFuture;
 use lorawan::PHYPayload;
 use semtech_udp::{
     pull_resp::{self, Time},
@@ -36,6 +41,27 @@ pub enum GatewayError {
     BeaconTxFailure,
 }
 
+/// The result of a radio transmission with the concentrator timestamp of the
+/// transmission, if the packet forwarder reports it.
+pub type TxResult = std::result::Result<Option<u32>, SemtechError>;
+
+/// A radio the gateway transmits LoRaWAN downlinks and PoC beacons with. The
+/// gateway uses the packet forwarder connected over Semtech UDP unless another
+/// radio is set with [`Gateway::with_radio`].
+pub trait RadioSink: Send {
+    /// Prepares the transmission of the given packet through the packet
+    /// forwarder with the given mac address. Nothing is transmitted until the
+    /// returned future is polled.
+    fn transmit(&self, mac: MacAddress, txpk: pull_resp::TxPk) -> BoxFuture<'static, TxResult>;
+}
+
+impl RadioSink for UdpRuntime {
+    fn transmit(&self, mac: MacAddress, txpk: pull_resp::TxPk) -> BoxFuture<'static, TxResult> {
+        let downlink = self.prepare_downlink(txpk, mac);
+        Box::pin(async move { downlink.dispatch(Some(DOWNLINK_TIMEOUT)).await })
+    }
+}
+
 pub type MessageSender = sync::MessageSender<Message>;
 pub type MessageReceiver = sync::MessageReceiver<Message>;
 
@@ -66,9 +92,12 @@ pub struct Gateway {
     beacons: beaconer::MessageSender,
     downlink_mac: MacAddress,
     udp_runtime: UdpRuntime,
+    radio: Option<Box<dyn RadioSink>>,
     listen_address: String,
     region_watch: region_watcher::MessageReceiver,
     region_params: RegionParams,
+    audit: AuditLog,
+    heartbeat: Heartbeat,
 }
 
 impl Gateway {
@@ -78,6 +107,7 @@ impl Gateway {
         region_watch: region_watcher::MessageReceiver,
         uplinks: packet_router::MessageSender,
         beacons: beaconer::MessageSender,
+        audit: AuditLog,
     ) -> Result<Self> {
         let region_params = region_watcher::current_value(&region_watch);
         let public_key = settings.keypair.public_key().clone();
@@ -89,12 +119,31 @@ impl Gateway {
             downlink_mac: Default::default(),
             listen_address: settings.listen.clone(),
             udp_runtime: UdpRuntime::new(&settings.listen).await.map_err(Box::new)?,
+            radio: None,
             region_watch,
             region_params,
+            audit,
+            heartbeat: Heartbeat::default(),
         };
         Ok(gateway)
     }
 
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Transmits downlinks and beacons with the given radio instead of the
+    /// connected packet forwarder.
+    pub fn with_radio(mut self, radio: Box<dyn RadioSink>) -> Self {
+        self.radio = Some(radio);
+        self
+    }
+
+    fn radio(&self) -> &dyn RadioSink {
+        self.radio.as_deref().unwrap_or(&self.udp_runtime)
+    }
+
     pub async fn run(&mut self, shutdown: &triggered::Listener) -> Result {
         info!(listen = &self.listen_address, "starting");
         loop {
@@ -103,6 +152,7 @@ impl Gateway {
                     info!( "shutting down");
                     return Ok(())
                 },
+                _ = self.heartbeat.tick() => (),
                 event = self.udp_runtime.recv() =>
                     self.handle_udp_event(event).await?,
                 message = self.messages.recv() => match message {
@@ -145,15 +195,11 @@ impl Gateway {
             }
             Event::PacketReceived(rxpk, _gateway_mac) => {
                 match PacketUp::from_rxpk(rxpk, &self.public_key, self.region_params.region) {
-                    Ok(packet) if packet.is_potential_beacon() => {
-                        self.handle_potential_beacon(packet).await;
-                    }
-                    Ok(packet) if packet.is_uplink() => {
-                        self.handle_uplink(packet, Instant::now()).await
-                    }
-                    Ok(packet) => {
-                        info!(%packet, "ignoring non-uplink packet");
-                    }
+                    Ok(packet) => match packet.frame_type() {
+                        FrameType::Beacon => self.handle_potential_beacon(packet).await,
+                        FrameType::Uplink => self.handle_uplink(packet, Instant::now()).await,
+                        FrameType::Unknown => info!(%packet, "ignoring non-uplink packet"),
+                    },
                     Err(Error::Decode(DecodeError::CrcDisabled)) => {
                         debug!("ignoring packet with disabled crc");
                     }
@@ -180,6 +226,12 @@ impl Gateway {
             info!(downlink_mac = %self.downlink_mac, uplink = %packet, "ignored potential beacon, no region");
             return;
         }
+        let datarate_allowed = packet::datarate::from_proto(packet.datarate())
+            .is_ok_and(|rate| packet::datarate::is_allowed(&self.region_params.params, &rate));
+        if !datarate_allowed {
+            info!(downlink_mac = %self.downlink_mac, uplink = %packet, "ignored potential beacon, datarate not in region");
+            return;
+        }
         info!(downlink_mac = %self.downlink_mac, uplink = %packet, "received potential beacon");
         self.beacons.received_beacon(packet).await
     }
@@ -237,11 +289,12 @@ impl Gateway {
             }
         };
 
-        let beacon_tx = self.udp_runtime.prepare_downlink(packet, self.downlink_mac);
+        let audit = self.audit.clone();
+        let beacon_tx = self.radio().transmit(self.downlink_mac, packet.clone());
 
         tokio::spawn(async move {
             let beacon_id = beacon.beacon_id();
-            match beacon_tx.dispatch(Some(DOWNLINK_TIMEOUT)).await {
+            match beacon_tx.await {
                 Ok(tmst) => {
                     info!(
                         beacon_id,
@@ -249,6 +302,7 @@ impl Gateway {
                         ?tmst,
                         "beacon transmitted"
                     );
+                    audit.record(AuditRecord::beacon(&beacon, &packet, tx_power as i32, tmst));
                     responder.send(Ok(BeaconResp {
                         powe: tx_power as i32,
                         tmst: tmst.unwrap_or(0),
@@ -272,6 +326,12 @@ impl Gateway {
                                     ?tmst,
                                     "beacon transmitted with adjusted power output",
                                 );
+                                audit.record(AuditRecord::beacon(
+                                    &beacon,
+                                    &packet,
+                                    actual_power,
+                                    tmst,
+                                ));
                                 responder.send(Ok(BeaconResp {
                                     powe: actual_power,
                                     tmst: tmst.unwrap_or(0),
@@ -298,44 +358,56 @@ impl Gateway {
             }
         };
 
-        let (mut downlink_rx1, mut downlink_rx2) = (
-            // first downlink
-            self.udp_runtime.prepare_empty_downlink(self.downlink_mac),
-            // 2nd downlink window if requested by the router response
-            self.udp_runtime.prepare_empty_downlink(self.downlink_mac),
-        );
-
         let downlink_mac = self.downlink_mac;
+        let audit = self.audit.clone();
+
+        let txpk = match downlink.to_rx1_pull_resp(tx_power) {
+            Ok(txpk) => txpk,
+            Err(err) => {
+                warn!(%err, "ignoring invalid rx1 downlink");
+                return;
+            }
+        };
+        let rx1_record = AuditRecord::downlink("rx1", &txpk, &downlink);
+        info!(%downlink_mac, "rx1 downlink {txpk}",);
+        // first downlink
+        let downlink_rx1 = self.radio().transmit(downlink_mac, txpk);
+        // 2nd downlink window if requested by the router response, only
+        // transmitted if the first one fails
+        let downlink_rx2 = match downlink.to_rx2_pull_resp(tx_power) {
+            Ok(Some(txpk)) => Some((
+                AuditRecord::downlink("rx2", &txpk, &downlink),
+                txpk.to_string(),
+                self.radio().transmit(downlink_mac, txpk),
+            )),
+            _ => None,
+        };
 
         tokio::spawn(async move {
-            if let Ok(txpk) = downlink.to_rx1_pull_resp(tx_power) {
-                info!(%downlink_mac, "rx1 downlink {txpk}",);
-
-                downlink_rx1.set_packet(txpk);
-                match downlink_rx1.dispatch(Some(DOWNLINK_TIMEOUT)).await {
-                    // On a too early or too late error retry on the rx2 slot if available.
-                    Err(SemtechError::Ack(TxAckErr::TooEarly | TxAckErr::TooLate)) => {
-                        if let Ok(Some(txpk)) = downlink.to_rx2_pull_resp(tx_power) {
-                            info!(%downlink_mac, "rx2 downlink {txpk}");
-
-                            downlink_rx2.set_packet(txpk);
-                            match downlink_rx2.dispatch(Some(DOWNLINK_TIMEOUT)).await {
-                                Err(SemtechError::Ack(TxAckErr::AdjustedTransmitPower(_, _))) => {
-                                    warn!("rx2 downlink sent with adjusted transmit power");
-                                }
-                                Err(err) => warn!(%err, "ignoring rx2 downlink error"),
-                                _ => (),
+            match downlink_rx1.await {
+                // On a too early or too late error retry on the rx2 slot if available.
+                Err(SemtechError::Ack(TxAckErr::TooEarly | TxAckErr::TooLate)) => {
+                    if let Some((rx2_record, txpk, downlink_rx2)) = downlink_rx2 {
+                        info!(%downlink_mac, "rx2 downlink {txpk}");
+
+                        match downlink_rx2.await {
+                            Err(SemtechError::Ack(TxAckErr::AdjustedTransmitPower(_, _))) => {
+                                warn!("rx2 downlink sent with adjusted transmit power");
+                                audit.record(rx2_record);
                             }
+                            Err(err) => warn!(%err, "ignoring rx2 downlink error"),
+                            Ok(_) => audit.record(rx2_record),
                         }
                     }
-                    Err(SemtechError::Ack(TxAckErr::AdjustedTransmitPower(_, _))) => {
-                        warn!("rx1 downlink sent with adjusted transmit power");
-                    }
-                    Err(err) => {
-                        warn!(%err, "ignoring rx1 downlink error");
-                    }
-                    Ok(_) => (),
                 }
+                Err(SemtechError::Ack(TxAckErr::AdjustedTransmitPower(_, _))) => {
+                    warn!("rx1 downlink sent with adjusted transmit power");
+                    audit.record(rx1_record);
+                }
+                Err(err) => {
+                    warn!(%err, "ignoring rx1 downlink error");
+                }
+                Ok(_) => audit.record(rx1_record),
             }
         });
     }
@@ -361,3 +433,214 @@ pub fn beacon_to_pull_resp(beacon: &Beacon, tx_power: u64) -> Result<pull_resp::
         ncrc: None,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region_watcher::test::mk_region_params_res;
+    use helium_proto::services::router::{PacketRouterPacketDownV1, WindowV1};
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+    use tokio::sync::{mpsc, watch};
+
+    /// A radio that records the packets it transmits and answers with
+    /// scripted results, succeeding once the script runs out.
+    struct MockRadio {
+        transmitted: mpsc::UnboundedSender<pull_resp::TxPk>,
+        results: Arc<Mutex<VecDeque<TxResult>>>,
+    }
+
+    impl MockRadio {
+        fn new(
+            results: impl IntoIterator<Item = TxResult>,
+        ) -> (Self, mpsc::UnboundedReceiver<pull_resp::TxPk>) {
+            let (transmitted, transmitted_rx) = mpsc::unbounded_channel();
+            let radio = Self {
+                transmitted,
+                results: Arc::new(Mutex::new(results.into_iter().collect())),
+            };
+            (radio, transmitted_rx)
+        }
+    }
+
+    impl RadioSink for MockRadio {
+        fn transmit(
+            &self,
+            _mac: MacAddress,
+            txpk: pull_resp::TxPk,
+        ) -> BoxFuture<'static, TxResult> {
+            let transmitted = self.transmitted.clone();
+            let results = self.results.clone();
+            Box::pin(async move {
+                let _ = transmitted.send(txpk);
+                let result = results.lock().expect("results").pop_front();
+                result.unwrap_or(Ok(None))
+            })
+        }
+    }
+
+    async fn mk_gateway(
+        radio: MockRadio,
+        audit: AuditLog,
+    ) -> (Gateway, MessageSender, region_watcher::MessageSender) {
+        let region_params =
+            RegionParams::try_from(mk_region_params_res(100)).expect("region params");
+        let (region_tx, region_watch) = watch::channel(region_params.clone());
+        let (messages_tx, messages) = message_channel();
+        let (uplinks, _) = packet_router::message_channel();
+        let (beacons, _) = beaconer::message_channel();
+        let gateway = Gateway {
+            public_key: crate::Keypair::new().public_key().clone(),
+            messages,
+            uplinks,
+            beacons,
+            downlink_mac: Default::default(),
+            udp_runtime: UdpRuntime::new("127.0.0.1:0").await.expect("udp runtime"),
+            radio: None,
+            listen_address: "127.0.0.1:0".to_string(),
+            region_watch,
+            region_params,
+            audit,
+            heartbeat: Heartbeat::default(),
+        }
+        .with_radio(Box::new(radio));
+        (gateway, messages_tx, region_tx)
+    }
+
+    fn mk_downlink(rx1_frequency: u32) -> PacketDown {
+        let window = |frequency| WindowV1 {
+            timestamp: 1_000_000,
+            frequency,
+            datarate: helium_proto::DataRate::Sf10bw500 as i32,
+            immediate: false,
+        };
+        PacketDown::from(PacketRouterPacketDownV1 {
+            payload: vec![0x60, 0x04, 0x03, 0x02, 0x01, 0x00, 0x01, 0x00],
+            rx1: Some(window(rx1_frequency)),
+            rx2: Some(window(923_300_000)),
+        })
+    }
+
+    async fn next_transmitted(
+        transmitted: &mut mpsc::UnboundedReceiver<pull_resp::TxPk>,
+    ) -> pull_resp::TxPk {
+        tokio::time::timeout(Duration::from_secs(5), transmitted.recv())
+            .await
+            .expect("transmitted in time")
+            .expect("transmitted packet")
+    }
+
+    #[tokio::test]
+    async fn test_radio_sink() {
+        let (radio, mut transmitted) = MockRadio::new([
+            Err(SemtechError::Ack(TxAckErr::TooLate)),
+            Ok(None),
+            Ok(None),
+            Ok(None),
+            Ok(Some(1234)),
+        ]);
+        let (mut gateway, messages, region_tx) = mk_gateway(radio, AuditLog::default()).await;
+        let (trigger, shutdown) = triggered::trigger();
+
+        let test = async {
+            // A late rx1 downlink is retried in rx2
+            messages.downlink(mk_downlink(927_500_000)).await;
+            let rx1 = next_transmitted(&mut transmitted).await;
+            assert_eq!(927.5, rx1.freq);
+            let rx2 = next_transmitted(&mut transmitted).await;
+            assert_eq!(923.3, rx2.freq);
+
+            // A successful rx1 downlink does not transmit in rx2, so the next
+            // transmission is the rx1 of the next downlink
+            messages.downlink(mk_downlink(927_500_000)).await;
+            assert_eq!(927.5, next_transmitted(&mut transmitted).await.freq);
+            messages.downlink(mk_downlink(925_100_000)).await;
+            assert_eq!(925.1, next_transmitted(&mut transmitted).await.freq);
+
+            // Beacons go out through the same radio
+            let region_params = region_tx.borrow().clone();
+            let beacon = Beacon::new(
+                beacon::Entropy::local().expect("remote entropy"),
+                beacon::Entropy::local().expect("local entropy"),
+                &region_params,
+            )
+            .expect("beacon");
+            let beacon_resp = messages.transmit_beacon(beacon).await.expect("beacon resp");
+            assert_eq!(1234, beacon_resp.tmst);
+            let beacon_txpk = next_transmitted(&mut transmitted).await;
+            assert!(!beacon_txpk.ipol);
+
+            trigger.trigger();
+        };
+        let (result, _) = tokio::join!(gateway.run(&shutdown), test);
+        result.expect("gateway run");
+    }
+
+    #[tokio::test]
+    async fn test_transmit_audit() {
+        let dir = std::env::temp_dir().join(format!("gateway_audit_{}", std::process::id()));
+        let (audit, guard) = AuditLog::with_dir(&dir).expect("audit log");
+        let (radio, mut transmitted) = MockRadio::new([
+            // rx1 too late, rx2 transmitted
+            Err(SemtechError::Ack(TxAckErr::TooLate)),
+            Ok(None),
+            // rx1 transmitted
+            Ok(None),
+            // rx1 too early, rx2 failed
+            Err(SemtechError::Ack(TxAckErr::TooEarly)),
+            Err(SemtechError::Ack(TxAckErr::TooLate)),
+            // beacon transmitted
+            Ok(Some(1234)),
+        ]);
+        let (mut gateway, messages, region_tx) = mk_gateway(radio, audit).await;
+        let (trigger, shutdown) = triggered::trigger();
+
+        // The mock radio completes a transmission in the poll that reports
+        // it, so the audit record of a transmission is written by the time
+        // the next transmission is awaited here
+        let test = async {
+            for (rx1_frequency, transmissions) in
+                [(927_500_000, 2), (925_100_000, 1), (926_300_000, 2)]
+            {
+                messages.downlink(mk_downlink(rx1_frequency)).await;
+                for _ in 0..transmissions {
+                    next_transmitted(&mut transmitted).await;
+                }
+            }
+            let region_params = region_tx.borrow().clone();
+            let beacon = Beacon::new(
+                beacon::Entropy::local().expect("remote entropy"),
+                beacon::Entropy::local().expect("local entropy"),
+                &region_params,
+            )
+            .expect("beacon");
+            let beacon_id = beacon.beacon_id();
+            messages.transmit_beacon(beacon).await.expect("beacon resp");
+            trigger.trigger();
+            beacon_id
+        };
+        let (result, beacon_id) = tokio::join!(gateway.run(&shutdown), test);
+        result.expect("gateway run");
+
+        // Dropping the guard flushes the outstanding records
+        drop(gateway);
+        drop(guard);
+        let records = crate::audit::test::read_records(&dir);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // Every transmitted packet is recorded exactly once, failed
+        // transmissions are not recorded
+        assert_eq!(3, records.len());
+        assert_eq!("downlink", records[0]["type"]);
+        assert_eq!("rx2", records[0]["window"]);
+        assert_eq!(923_300_000, records[0]["frequency"]);
+        assert_eq!("downlink", records[1]["type"]);
+        assert_eq!("rx1", records[1]["window"]);
+        assert_eq!(925_100_000, records[1]["frequency"]);
+        assert_eq!("beacon", records[2]["type"]);
+        assert_eq!(beacon_id, records[2]["beacon_id"]);
+        assert_eq!(1234, records[2]["tmst"]);
+    }
+}
@@ -68,3 +68,22 @@ impl TryFrom<helium_proto::RoutingAddress> for KeyedUri {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Keypair;
+
+    #[test]
+    fn ipv6_keyed_uri() {
+        let keypair = Keypair::new();
+        let keyed_uri = KeyedUri::try_from(helium_proto::services::local::KeyedUri {
+            address: keypair.public_key().to_vec(),
+            uri: "http://[::1]:8080".to_string(),
+        })
+        .expect("keyed uri from ipv6 uri");
+        assert_eq!(Some("[::1]"), keyed_uri.uri.host());
+        assert_eq!(Some(8080), keyed_uri.uri.port_u16());
+        assert_eq!(keypair.public_key(), keyed_uri.pubkey.as_ref());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{DecodeError, Error, Result};
+use angry_purple_tiger::AnimalName;
 #[cfg(feature = "ecc608")]
 use helium_crypto::ecc608;
 #[cfg(feature = "tpm")]
@@ -164,6 +165,17 @@ impl Default for Keypair {
     }
 }
 
+/// Returns the human friendly three word "animal name" for the given public
+/// key. This is the name gateways are commonly identified by in explorers and
+/// apps.
+pub fn animal_name(public_key: &PublicKey) -> String {
+    public_key
+        .to_string()
+        .parse::<AnimalName>()
+        .expect("animal name")
+        .to_string()
+}
+
 #[derive(Debug)]
 struct KeypairArgs(HashMap<String, String>);
 
@@ -232,4 +244,12 @@ mod tests {
                 .expect("network")
         );
     }
+
+    #[test]
+    fn public_key_animal_name() {
+        let public_key =
+            PublicKey::from_str("112CuoXo7WCcp6GGwDNBo6H5nKXGH45UNJ39iEefdv2mwmnwdFt8")
+                .expect("public key");
+        assert_eq!("feisty-glass-dragon", animal_name(&public_key));
+    }
 }
@@ -1,3 +1,4 @@
+pub mod audit;
 pub mod beaconer;
 pub mod cmd;
 pub mod error;
@@ -13,6 +14,7 @@ pub mod server;
 pub mod service;
 pub mod settings;
 pub mod sync;
+pub mod watchdog;
 
 mod api;
 mod base64;
@@ -21,7 +23,7 @@ pub(crate) use crate::base64::Base64;
 pub use beacon::{Region, RegionParams};
 pub use error::{DecodeError, Error, Result};
 pub use keyed_uri::KeyedUri;
-pub use keypair::{Keypair, PublicKey, Sign, Verify};
+pub use keypair::{animal_name, Keypair, PublicKey, Sign, Verify};
 pub use packet::{PacketDown, PacketUp};
 pub use settings::Settings;
 
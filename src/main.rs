@@ -1,9 +1,13 @@
 use clap::Parser;
-use gateway_rs::{cmd, error::Result, settings::Settings};
+use gateway_rs::{
+    cmd,
+    error::Result,
+    settings::{log_level, LogSettings, Settings},
+};
 use std::path::PathBuf;
 use tokio::{io::AsyncReadExt, signal, time::Duration};
-use tracing::{debug, error, Level};
-use tracing_subscriber::prelude::*;
+use tracing::{debug, error, info, warn, Level};
+use tracing_subscriber::{filter::Targets, prelude::*, reload};
 
 #[derive(Debug, Parser)]
 #[command(version = env!("CARGO_PKG_VERSION"))]
@@ -30,12 +34,21 @@ pub enum Cmd {
     Add(Box<cmd::add::Cmd>),
 }
 
-fn setup_tracing(settings: &Settings) -> tracing_appender::non_blocking::WorkerGuard {
+fn mk_log_filter(level: log_level::Level) -> Targets {
+    Targets::new()
+        .with_target(env!("CARGO_BIN_NAME"), level)
+        .with_target("gateway_rs", level)
+        .with_default(Level::INFO)
+}
+
+fn setup_tracing(
+    settings: &Settings,
+) -> (
+    tracing_appender::non_blocking::WorkerGuard,
+    impl Fn(log_level::Level) + Send + 'static,
+) {
     let (non_blocking, guard) = tracing_appender::non_blocking(std::io::stdout());
-    let filter = tracing_subscriber::filter::Targets::new()
-        .with_target(env!("CARGO_BIN_NAME"), settings.log.level)
-        .with_target("gateway_rs", settings.log.level)
-        .with_default(Level::INFO);
+    let (filter, reload_level) = mk_reload_filter(settings.log.level);
 
     let stdout_log = tracing_subscriber::fmt::layer()
         .compact()
@@ -46,7 +59,52 @@ fn setup_tracing(settings: &Settings) -> tracing_appender::non_blocking::WorkerG
         .with(stdout_log)
         .with(filter)
         .init();
-    guard
+
+    (guard, reload_level)
+}
+
+/// Constructs a log filter layer for the given level. The level of the
+/// filter can be changed at runtime by calling the returned reload function.
+fn mk_reload_filter<S: 'static>(
+    level: log_level::Level,
+) -> (
+    reload::Layer<Targets, S>,
+    impl Fn(log_level::Level) + Send + 'static,
+) {
+    let (filter, filter_handle) = reload::Layer::new(mk_log_filter(level));
+    let reload_level = move |level: log_level::Level| {
+        if let Err(err) = filter_handle.reload(mk_log_filter(level)) {
+            warn!(%err, "failed to reload log level");
+        }
+    };
+    (filter, reload_level)
+}
+
+/// Reloads the log level from the settings file whenever the process
+/// receives a SIGHUP. This allows log verbosity to be changed without
+/// restarting the service.
+#[cfg(unix)]
+async fn reload_log_level_on_hangup(
+    config: PathBuf,
+    reload_level: impl Fn(log_level::Level) + Send + 'static,
+) {
+    use signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!(%err, "unable to listen for hangup signal");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match LogSettings::new(&config) {
+            Ok(log_settings) => {
+                info!(level = %log_settings.level, "reloading log level");
+                reload_level(log_settings.level);
+            }
+            Err(err) => warn!(%err, "failed to reload log settings"),
+        }
+    }
 }
 
 pub fn main() -> Result {
@@ -62,7 +120,7 @@ pub fn main() -> Result {
     // logger, simply calling `exit()` early prevents any error
     // logging from reaching its destination.
     let retcode = {
-        let _guard = setup_tracing(&settings);
+        let (_guard, reload_level) = setup_tracing(&settings);
 
         let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_all()
@@ -71,6 +129,11 @@ pub fn main() -> Result {
 
         // Start the runtime
         let res = runtime.block_on(async {
+            #[cfg(unix)]
+            tokio::spawn(reload_log_level_on_hangup(cli.config.clone(), reload_level));
+            #[cfg(not(unix))]
+            drop(reload_level);
+
             let (shutdown_trigger, shutdown_listener) = triggered::trigger();
             tokio::spawn(async move {
                 let mut in_buf = [0u8; 64];
@@ -108,3 +171,39 @@ pub async fn run(cli: Cli, settings: Settings, shutdown_listener: &triggered::Li
         Cmd::Server(cmd) => cmd.run(shutdown_listener, settings).await,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tracing_subscriber::{layer::Context, Layer};
+
+    struct CountingLayer(Arc<AtomicUsize>);
+
+    impl<S: tracing::Subscriber> Layer<S> for CountingLayer {
+        fn on_event(&self, _event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_reload_log_level() {
+        let events = Arc::new(AtomicUsize::new(0));
+        let (filter, reload_level) = mk_reload_filter(Level::INFO.into());
+        let subscriber = tracing_subscriber::registry()
+            .with(CountingLayer(events.clone()))
+            .with(filter);
+
+        tracing::subscriber::with_default(subscriber, || {
+            debug!("filtered at info");
+            assert_eq!(0, events.load(Ordering::SeqCst));
+
+            reload_level(Level::DEBUG.into());
+            debug!("recorded at debug");
+            assert_eq!(1, events.load(Ordering::SeqCst));
+        });
+    }
+}
@@ -16,13 +16,32 @@ use std::{
     ops::Deref,
     time::{SystemTime, UNIX_EPOCH},
 };
-
+use time::{Duration, OffsetDateTime};
+
+/// An uplink packet as received from the packet forwarder.
+///
+/// The `timestamp` of the packet is in the concentrator time domain: the
+/// value of the concentrator's free running 32 bit microsecond counter (tmst)
+/// at the end of the packet reception. It is not related to wall clock or GPS
+/// time. Use a [`TimeReference`] to convert between the two domains.
 #[derive(Debug, Clone, PartialEq)]
 pub struct PacketUp(PacketRouterPacketUpV1);
 
 #[derive(Debug, Clone)]
 pub struct PacketDown(PacketRouterPacketDownV1);
 
+/// The kind of frame carried by a received packet. This determines where a
+/// received packet is dispatched to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    /// A proprietary frame of beacon size
+    Beacon,
+    /// A LoRaWAN uplink frame to be delivered to the packet router
+    Uplink,
+    /// Anything else, like a downlink frame heard by the gateway
+    Unknown,
+}
+
 impl Deref for PacketUp {
     type Target = PacketRouterPacketUpV1;
 
@@ -42,6 +61,12 @@ impl From<&PacketUp> for PacketRouterPacketUpV1 {
     }
 }
 
+impl From<PacketRouterPacketUpV1> for PacketUp {
+    fn from(value: PacketRouterPacketUpV1) -> Self {
+        Self(value)
+    }
+}
+
 impl From<PacketRouterPacketDownV1> for PacketDown {
     fn from(value: PacketRouterPacketDownV1) -> Self {
         Self(value)
@@ -67,7 +92,7 @@ impl TryFrom<PacketUp> for poc_lora::LoraWitnessReportReqV1 {
     fn try_from(value: PacketUp) -> Result<Self> {
         let report = poc_lora::LoraWitnessReportReqV1 {
             data: vec![],
-            tmst: value.0.timestamp as u32,
+            tmst: value.tmst(),
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .map_err(Error::from)?
@@ -132,10 +157,33 @@ impl PacketUp {
             .unwrap_or(false)
     }
 
+    /// Returns the device address of the packet if it is a LoRaWAN data
+    /// frame. Join requests and other frames do not carry a device address.
+    pub fn dev_addr(&self) -> Option<u32> {
+        dev_addr(Direction::Uplink, self.payload())
+    }
+
+    pub fn frame_type(&self) -> FrameType {
+        if self.is_potential_beacon() {
+            FrameType::Beacon
+        } else if self.is_uplink() {
+            FrameType::Uplink
+        } else {
+            FrameType::Unknown
+        }
+    }
+
     pub fn payload(&self) -> &[u8] {
         &self.0.payload
     }
 
+    /// Returns the concentrator counter (tmst) value of the packet in
+    /// microseconds. Note that this counter wraps around roughly every 71
+    /// minutes.
+    pub fn tmst(&self) -> u32 {
+        self.0.timestamp as u32
+    }
+
     pub fn parse_header(payload: &[u8]) -> Result<MHDR> {
         use std::io::Cursor;
         lorawan::MHDR::read(&mut Cursor::new(payload)).map_err(Error::from)
@@ -158,6 +206,16 @@ impl PacketUp {
 }
 
 impl PacketDown {
+    pub fn payload(&self) -> &[u8] {
+        &self.0.payload
+    }
+
+    /// Returns the device address of the packet if it is a LoRaWAN data
+    /// frame.
+    pub fn dev_addr(&self) -> Option<u32> {
+        dev_addr(Direction::Downlink, self.payload())
+    }
+
     pub fn to_rx1_pull_resp(&self, tx_power: u32) -> Result<pull_resp::TxPk> {
         let rx1 = self.0.rx1.as_ref().ok_or_else(DecodeError::no_rx1_window)?;
         let time = if rx1.immediate {
@@ -214,6 +272,146 @@ impl PacketDown {
     }
 }
 
+/// Correlates a concentrator counter (tmst) value with a wall clock (UTC or
+/// GPS derived) time. Given such a reference other concentrator timestamps can
+/// be converted to wall clock time and back.
+///
+/// Since the concentrator counter is a wrapping 32 bit microsecond counter,
+/// conversions are only meaningful for times within about 35 minutes of the
+/// reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeReference {
+    pub tmst: u32,
+    pub time: OffsetDateTime,
+}
+
+impl TimeReference {
+    pub fn new(tmst: u32, time: OffsetDateTime) -> Self {
+        Self { tmst, time }
+    }
+
+    /// Converts the given concentrator timestamp to wall clock time.
+    pub fn to_time(&self, tmst: u32) -> OffsetDateTime {
+        // Interpret the wrapped difference as signed to allow for timestamps
+        // both before and after the reference across a counter wrap
+        let delta = tmst.wrapping_sub(self.tmst) as i32;
+        self.time + Duration::microseconds(delta as i64)
+    }
+
+    /// Converts the given wall clock time to a concentrator timestamp.
+    pub fn to_tmst(&self, time: OffsetDateTime) -> u32 {
+        let delta = (time - self.time).whole_microseconds() as i64;
+        self.tmst.wrapping_add(delta as u32)
+    }
+}
+
+fn dev_addr(direction: Direction, payload: &[u8]) -> Option<u32> {
+    match PacketUp::parse_frame(direction, payload) {
+        Ok(PHYPayloadFrame::MACPayload(payload)) => Some(payload.dev_addr()),
+        _ => None,
+    }
+}
+
+/// The LoRa modulation settings besides the data rate that determine the time
+/// on air of a packet. The default is what LoRaWAN uses: a 4/5 coding rate and
+/// an 8 symbol preamble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AirtimeParams {
+    /// Number of redundancy bits per 4 data bits, 1 for 4/5 up to 4 for 4/8
+    pub coding_rate: u64,
+    /// Number of programmed preamble symbols
+    pub preamble_symbols: u64,
+}
+
+impl Default for AirtimeParams {
+    fn default() -> Self {
+        Self {
+            coding_rate: 1,
+            preamble_symbols: 8,
+        }
+    }
+}
+
+impl AirtimeParams {
+    pub fn new(coding_rate: &CodingRate, preamble_symbols: Option<u64>) -> Self {
+        let coding_rate = if matches!(coding_rate, CodingRate::_4_8) {
+            4
+        } else if matches!(coding_rate, CodingRate::_4_7) {
+            3
+        } else if matches!(coding_rate, CodingRate::_4_6) {
+            2
+        } else {
+            Self::default().coding_rate
+        };
+        Self {
+            coding_rate,
+            preamble_symbols: preamble_symbols.unwrap_or(Self::default().preamble_symbols),
+        }
+    }
+
+    /// Returns the settings a packet forwarder transmits the given packet with
+    pub fn from_txpk(txpk: &pull_resp::TxPk) -> Self {
+        Self::new(&txpk.codr, txpk.prea)
+    }
+}
+
+/// Returns the time on air of a LoRa packet with the given payload size, data
+/// rate and modulation settings. This assumes an explicit header and an
+/// enabled CRC, as used by LoRaWAN.
+///
+/// Returns None for spreading factors outside the LoRaWAN range of SF7 to
+/// SF12, which use a different symbol layout, and for settings whose time on
+/// air overflows.
+pub(crate) fn airtime(
+    rate: &DataRate,
+    params: &AirtimeParams,
+    payload_size: usize,
+) -> Option<std::time::Duration> {
+    use semtech_udp::{Bandwidth, SpreadingFactor};
+
+    let sf: i64 = match rate.spreading_factor() {
+        SpreadingFactor::SF5 => 5,
+        SpreadingFactor::SF6 => 6,
+        SpreadingFactor::SF7 => 7,
+        SpreadingFactor::SF8 => 8,
+        SpreadingFactor::SF9 => 9,
+        SpreadingFactor::SF10 => 10,
+        SpreadingFactor::SF11 => 11,
+        SpreadingFactor::SF12 => 12,
+    };
+    if !(7..=12).contains(&sf) {
+        return None;
+    }
+    let bandwidth_hz: u64 = match rate.bandwidth() {
+        Bandwidth::BW125 => 125_000,
+        Bandwidth::BW250 => 250_000,
+        Bandwidth::BW500 => 500_000,
+    };
+    let symbol_us = 1u64.checked_shl(sf as u32)?.checked_mul(1_000_000)? / bandwidth_hz;
+    // Low data rate optimization is required for symbol times over 16ms
+    let low_dr_optimize = i64::from(symbol_us > 16_000);
+    let payload_bits = i64::try_from(payload_size)
+        .ok()?
+        .checked_mul(8)?
+        .checked_add(28 + 16 - 4 * sf)?;
+    let symbol_bits = 4 * (sf - 2 * low_dr_optimize);
+    let coding_symbols = i64::try_from(params.coding_rate).ok()?.checked_add(4)?;
+    let payload_symbols = payload_bits
+        .checked_add(symbol_bits - 1)?
+        .div_euclid(symbol_bits)
+        .checked_mul(coding_symbols)?
+        .max(0)
+        .checked_add(8)?;
+    // Preamble length in quarter symbols, with 4.25 symbols for the sync word
+    let preamble_quarter_symbols = params.preamble_symbols.checked_mul(4)?.checked_add(17)?;
+    let airtime_us = (preamble_quarter_symbols.checked_mul(symbol_us)? / 4).checked_add(
+        u64::try_from(payload_symbols)
+            .ok()?
+            .checked_mul(symbol_us)?,
+    )?;
+    Some(std::time::Duration::from_micros(airtime_us))
+}
+
 pub(crate) fn to_hz<M: Into<f64>>(mhz: M) -> u64 {
     (mhz.into() * 1_000_000f64).trunc() as u64
 }
@@ -224,7 +422,7 @@ pub(crate) fn to_mhz<H: Into<f64>>(hz: H) -> f64 {
 
 pub(crate) mod datarate {
     use super::{DecodeError, Result};
-    use helium_proto::DataRate as ProtoRate;
+    use helium_proto::{BlockchainRegionParamV1, DataRate as ProtoRate, RegionSpreading};
     use semtech_udp::{Bandwidth, DataRate, SpreadingFactor};
 
     pub fn from_proto(rate: ProtoRate) -> Result<DataRate> {
@@ -292,4 +490,281 @@ pub(crate) mod datarate {
         };
         Ok(rate)
     }
+
+    /// Returns the region spreading for the spreading factor of the given
+    /// datarate. This allows received or transmitted datarates to be checked
+    /// against the tagged spreading of region params. Spreading factors not
+    /// used in region params return None.
+    pub fn spreading_from_datarate(rate: &DataRate) -> Option<RegionSpreading> {
+        let spreading = match rate.spreading_factor() {
+            SpreadingFactor::SF7 => RegionSpreading::Sf7,
+            SpreadingFactor::SF8 => RegionSpreading::Sf8,
+            SpreadingFactor::SF9 => RegionSpreading::Sf9,
+            SpreadingFactor::SF10 => RegionSpreading::Sf10,
+            SpreadingFactor::SF11 => RegionSpreading::Sf11,
+            SpreadingFactor::SF12 => RegionSpreading::Sf12,
+            SpreadingFactor::SF6 | SpreadingFactor::SF5 => return None,
+        };
+        Some(spreading)
+    }
+
+    /// Returns whether the spreading factor of the given datarate is tagged
+    /// on any of the given region params channels.
+    pub fn is_allowed(params: &[BlockchainRegionParamV1], rate: &DataRate) -> bool {
+        spreading_from_datarate(rate).is_some_and(|spreading| {
+            params
+                .iter()
+                .filter_map(|param| param.spreading.as_ref())
+                .flat_map(|param_spreading| param_spreading.tagged_spreading.iter())
+                .any(|tagged| tagged.region_spreading == spreading as i32)
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{FrameType, PacketRouterPacketUpV1, PacketUp, TimeReference};
+    use time::{macros::datetime, Duration};
+
+    /// Unconfirmed data frame for device address 01020304
+    pub(crate) const DATA_FRAME: [u8; 14] = [
+        0x40, 0x04, 0x03, 0x02, 0x01, 0x00, 0x01, 0x00, 0x01, 0xaa, 0x01, 0x02, 0x03, 0x04,
+    ];
+
+    fn packet_up(payload: Vec<u8>) -> PacketUp {
+        PacketUp(PacketRouterPacketUpV1 {
+            payload,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_packet_classification() {
+        // Proprietary frame of beacon size
+        let mut payload = vec![0xe0];
+        payload.resize(beacon::BEACON_PAYLOAD_SIZE + PacketUp::header_size(), 0);
+        let beacon = packet_up(payload);
+        assert!(beacon.is_potential_beacon());
+        assert!(!beacon.is_uplink());
+        assert_eq!(FrameType::Beacon, beacon.frame_type());
+
+        // Proprietary frame of the wrong size
+        let proprietary = packet_up(vec![0xe0, 0x01, 0x02, 0x03]);
+        assert!(!proprietary.is_potential_beacon());
+        assert!(!proprietary.is_uplink());
+        assert_eq!(FrameType::Unknown, proprietary.frame_type());
+
+        // Unconfirmed data up frame padded to beacon size
+        let mut payload = DATA_FRAME.to_vec();
+        payload.resize(beacon::BEACON_PAYLOAD_SIZE + PacketUp::header_size(), 0);
+        let uplink = packet_up(payload);
+        assert!(!uplink.is_potential_beacon());
+        assert!(uplink.is_uplink());
+        assert_eq!(FrameType::Uplink, uplink.frame_type());
+    }
+
+    #[test]
+    fn test_datarate_roundtrip() {
+        use super::datarate;
+        use helium_proto::DataRate as ProtoRate;
+        use semtech_udp::{Bandwidth, DataRate, SpreadingFactor};
+
+        for (spreading_factor, proto_rate) in [
+            (SpreadingFactor::SF7, ProtoRate::Sf7bw125),
+            (SpreadingFactor::SF8, ProtoRate::Sf8bw125),
+            (SpreadingFactor::SF9, ProtoRate::Sf9bw125),
+            (SpreadingFactor::SF10, ProtoRate::Sf10bw125),
+            (SpreadingFactor::SF11, ProtoRate::Sf11bw125),
+            (SpreadingFactor::SF12, ProtoRate::Sf12bw125),
+        ] {
+            let rate = DataRate::new(spreading_factor, Bandwidth::BW125);
+            let rate_str = rate.to_string();
+            assert_eq!(
+                proto_rate,
+                datarate::to_proto(rate).expect("proto datarate")
+            );
+            assert_eq!(
+                rate_str,
+                datarate::from_proto(proto_rate)
+                    .expect("datarate")
+                    .to_string()
+            );
+        }
+
+        assert!(datarate::to_proto(DataRate::new(SpreadingFactor::SF6, Bandwidth::BW125)).is_err());
+        assert!(datarate::from_proto(ProtoRate::Fsk50).is_err());
+    }
+
+    #[test]
+    fn test_spreading_from_datarate() {
+        use super::datarate;
+        use helium_proto::RegionSpreading;
+        use semtech_udp::{Bandwidth, DataRate, SpreadingFactor};
+
+        for (spreading_factor, spreading) in [
+            (SpreadingFactor::SF7, RegionSpreading::Sf7),
+            (SpreadingFactor::SF8, RegionSpreading::Sf8),
+            (SpreadingFactor::SF9, RegionSpreading::Sf9),
+            (SpreadingFactor::SF10, RegionSpreading::Sf10),
+            (SpreadingFactor::SF11, RegionSpreading::Sf11),
+            (SpreadingFactor::SF12, RegionSpreading::Sf12),
+        ] {
+            let rate = DataRate::new(spreading_factor, Bandwidth::BW125);
+            assert_eq!(Some(spreading), datarate::spreading_from_datarate(&rate));
+        }
+
+        // The spreading does not depend on the bandwidth
+        assert_eq!(
+            Some(RegionSpreading::Sf12),
+            datarate::spreading_from_datarate(&DataRate::new(
+                SpreadingFactor::SF12,
+                Bandwidth::BW500
+            ))
+        );
+
+        assert_eq!(
+            None,
+            datarate::spreading_from_datarate(&DataRate::new(
+                SpreadingFactor::SF6,
+                Bandwidth::BW125
+            ))
+        );
+    }
+
+    #[test]
+    fn test_datarate_is_allowed() {
+        use super::datarate;
+        use helium_proto::{
+            BlockchainRegionParamV1, BlockchainRegionSpreadingV1, RegionSpreading, TaggedSpreading,
+        };
+        use semtech_udp::{Bandwidth, DataRate, SpreadingFactor};
+
+        let params: Vec<BlockchainRegionParamV1> = [RegionSpreading::Sf9, RegionSpreading::Sf10]
+            .into_iter()
+            .map(|region_spreading| BlockchainRegionParamV1 {
+                channel_frequency: 903_900_000,
+                bandwidth: 125_000,
+                max_eirp: 360,
+                spreading: Some(BlockchainRegionSpreadingV1 {
+                    tagged_spreading: vec![TaggedSpreading {
+                        region_spreading: region_spreading as i32,
+                        max_packet_size: 24,
+                    }],
+                }),
+            })
+            .collect();
+
+        let rate = |spreading_factor| DataRate::new(spreading_factor, Bandwidth::BW125);
+        assert!(datarate::is_allowed(&params, &rate(SpreadingFactor::SF9)));
+        assert!(datarate::is_allowed(&params, &rate(SpreadingFactor::SF10)));
+        assert!(!datarate::is_allowed(&params, &rate(SpreadingFactor::SF7)));
+        assert!(!datarate::is_allowed(&params, &rate(SpreadingFactor::SF6)));
+        assert!(!datarate::is_allowed(&[], &rate(SpreadingFactor::SF9)));
+    }
+
+    #[test]
+    fn test_airtime() {
+        use super::{airtime, AirtimeParams};
+        use semtech_udp::{Bandwidth, CodingRate, DataRate, SpreadingFactor};
+
+        // Reference values from the Semtech LoRa airtime calculator
+        for (spreading_factor, payload_size, expected_us) in [
+            (SpreadingFactor::SF7, 13, 46_336),
+            (SpreadingFactor::SF10, 13, 288_768),
+            (SpreadingFactor::SF12, 13, 1_155_072),
+            (SpreadingFactor::SF12, 51, 2_465_792),
+        ] {
+            let rate = DataRate::new(spreading_factor, Bandwidth::BW125);
+            assert_eq!(
+                expected_us,
+                airtime(&rate, &AirtimeParams::default(), payload_size)
+                    .expect("airtime")
+                    .as_micros(),
+                "{rate} {payload_size}"
+            );
+        }
+
+        // More redundancy bits or a longer preamble make the packet longer
+        let rate = DataRate::new(SpreadingFactor::SF7, Bandwidth::BW125);
+        assert_eq!(
+            AirtimeParams::default(),
+            AirtimeParams::new(&CodingRate::_4_5, None)
+        );
+        let airtime_us = |params| airtime(&rate, &params, 13).map(|time| time.as_micros());
+        assert_eq!(
+            Some(61_696),
+            airtime_us(AirtimeParams::new(&CodingRate::_4_8, None))
+        );
+        assert_eq!(
+            Some(54_528),
+            airtime_us(AirtimeParams::new(&CodingRate::_4_5, Some(16)))
+        );
+    }
+
+    #[test]
+    fn test_airtime_out_of_range() {
+        use super::{airtime, AirtimeParams};
+        use semtech_udp::{Bandwidth, DataRate, SpreadingFactor};
+
+        // Spreading factors outside of SF7 to SF12 are not computed
+        for spreading_factor in [SpreadingFactor::SF5, SpreadingFactor::SF6] {
+            let rate = DataRate::new(spreading_factor, Bandwidth::BW125);
+            assert_eq!(None, airtime(&rate, &AirtimeParams::default(), 13));
+        }
+
+        // Settings that overflow the computation are rejected too
+        let rate = DataRate::new(SpreadingFactor::SF12, Bandwidth::BW125);
+        let params = AirtimeParams {
+            preamble_symbols: u64::MAX,
+            ..Default::default()
+        };
+        assert_eq!(None, airtime(&rate, &params, 13));
+        assert_eq!(None, airtime(&rate, &AirtimeParams::default(), usize::MAX));
+    }
+
+    #[test]
+    fn test_dev_addr() {
+        let uplink = packet_up(DATA_FRAME.to_vec());
+        assert_eq!(Some(0x01020304), uplink.dev_addr());
+
+        // Join requests carry no device address
+        let mut payload = vec![0x00];
+        payload.resize(23, 0);
+        assert_eq!(None, packet_up(payload).dev_addr());
+    }
+
+    #[test]
+    fn test_time_reference() {
+        let time = datetime!(2023-09-01 09:20 UTC);
+        let reference = TimeReference::new(1_000_000, time);
+
+        assert_eq!(time, reference.to_time(1_000_000));
+        assert_eq!(time + Duration::seconds(2), reference.to_time(3_000_000));
+        assert_eq!(
+            time - Duration::milliseconds(500),
+            reference.to_time(500_000)
+        );
+        assert_eq!(3_000_000, reference.to_tmst(time + Duration::seconds(2)));
+        assert_eq!(
+            500_000,
+            reference.to_tmst(time - Duration::milliseconds(500))
+        );
+    }
+
+    #[test]
+    fn test_time_reference_wrap() {
+        let time = datetime!(2023-09-01 09:20 UTC);
+        // Reference just before the counter wraps around
+        let reference = TimeReference::new(u32::MAX - 999, time);
+
+        let after = time + Duration::milliseconds(2);
+        assert_eq!(1000, reference.to_tmst(after));
+        assert_eq!(after, reference.to_time(1000));
+
+        // And a reference just after a wrap looking back
+        let reference = TimeReference::new(1000, time);
+        let before = time - Duration::milliseconds(2);
+        assert_eq!(u32::MAX - 999, reference.to_tmst(before));
+        assert_eq!(before, reference.to_time(u32::MAX - 999));
+    }
 }
@@ -1,8 +1,12 @@
 use crate::{
+    audit::{AuditLog, AuditRecord},
     gateway,
     message_cache::{CacheMessage, MessageCache},
     service::{packet_router::PacketRouterService, Reconnect},
-    sync, Base64, PacketUp, PublicKey, Result, Settings,
+    settings::RouterMode,
+    sync,
+    watchdog::Heartbeat,
+    Base64, PacketUp, PublicKey, Result, Settings,
 };
 use futures::TryFutureExt;
 use helium_proto::services::router::{
@@ -53,34 +57,97 @@ impl MessageSender {
 pub struct PacketRouter {
     messages: MessageReceiver,
     transmit: gateway::MessageSender,
+    mode: RouterMode,
+    /// Connections to the configured packet routers ordered by priority
+    routers: Vec<RouterConnection>,
+    audit: AuditLog,
+    heartbeat: Heartbeat,
+}
+
+/// A connection to a packet router with its own session and queue of uplinks
+/// waiting to be sent
+struct RouterConnection {
     service: PacketRouterService,
     reconnect: Reconnect,
     store: MessageCache<PacketUp>,
 }
 
+enum ConnectionEvent {
+    Reconnect,
+    Received(Result<envelope_down_v1::Data>),
+}
+
+impl RouterConnection {
+    async fn next_event(&mut self) -> ConnectionEvent {
+        tokio::select! {
+            _ = self.reconnect.wait() => ConnectionEvent::Reconnect,
+            message = self.service.recv() => ConnectionEvent::Received(message),
+        }
+    }
+
+    async fn handle_reconnect(&mut self) -> Result {
+        // Do not send waiting packets on ok here since we wait for a session
+        // offer. Also do not reset the reconnect retry counter since only a
+        // session key indicates a good connection
+        let uri = self.service.uri.clone();
+        self.service
+            .reconnect()
+            .inspect_err(|err| warn!(%uri, %err, "failed to reconnect"))
+            .await
+    }
+}
+
+/// Waits for the next event on any of the given router connections and
+/// returns it with the index of its connection
+async fn next_router_event(routers: &mut [RouterConnection]) -> (usize, ConnectionEvent) {
+    let events = routers
+        .iter_mut()
+        .map(|router| Box::pin(router.next_event()));
+    let (event, index, _) = futures::future::select_all(events).await;
+    (index, event)
+}
+
 impl PacketRouter {
     pub fn new(
         settings: &Settings,
         messages: MessageReceiver,
         transmit: gateway::MessageSender,
+        audit: AuditLog,
     ) -> Self {
         let router_settings = &settings.router;
-        let service =
-            PacketRouterService::new(router_settings.uri.clone(), settings.keypair.clone());
-        let store = MessageCache::new(router_settings.queue);
-        let reconnect = Reconnect::default();
+        let routers = router_settings
+            .uris()
+            .into_iter()
+            .map(|uri| RouterConnection {
+                service: PacketRouterService::new(
+                    uri,
+                    settings.keypair.clone(),
+                    router_settings.keepalive(),
+                ),
+                reconnect: Reconnect::default(),
+                store: MessageCache::new(router_settings.queue),
+            })
+            .collect();
         Self {
-            service,
             transmit,
             messages,
-            store,
-            reconnect,
+            mode: router_settings.mode,
+            routers,
+            audit,
+            heartbeat: Heartbeat::default(),
         }
     }
 
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
     #[tracing::instrument(skip_all)]
     pub async fn run(&mut self, shutdown: &triggered::Listener) -> Result {
-        info!(uri = %self.service.uri, "starting");
+        for router in &self.routers {
+            info!(uri = %router.service.uri, mode = ?self.mode, "starting");
+        }
 
         loop {
             tokio::select! {
@@ -88,98 +155,329 @@ impl PacketRouter {
                     info!("shutting down");
                     return Ok(())
                 },
+                _ = self.heartbeat.tick() => (),
                 message = self.messages.recv() => match message {
                     Some(Message::Uplink{packet, received}) =>
-                        if self.handle_uplink(packet, received).await.is_err() {
-                            self.service.disconnect();
-                            warn!("router disconnected");
-                            self.reconnect.update_next_time(true);
-                        },
-                    Some(Message::Status(tx_resp)) => {
-                        let status = RouterStatus {
-                            uri: self.service.uri.clone(),
-                            connected: self.service.is_connected(),
-                            session_key: self.service.session_key().cloned(),
-                        };
-                        tx_resp.send(status)
-                    }
+                        self.handle_uplink(packet, received).await,
+                    Some(Message::Status(tx_resp)) => tx_resp.send(self.status()),
                     None => warn!("ignoring closed message channel"),
                 },
-                _ = self.reconnect.wait() => {
-                    let reconnect_result = self.handle_reconnect().await;
-                    self.reconnect.update_next_time(reconnect_result.is_err());
-                },
-                router_message = self.service.recv() => match router_message {
-                    Ok(envelope_down_v1::Data::Packet(message)) => self.handle_downlink(message).await,
-                    Ok(envelope_down_v1::Data::SessionOffer(message)) => {
-                        let session_result = self.handle_session_offer(message).await;
+                (index, event) = next_router_event(&mut self.routers) => match event {
+                    ConnectionEvent::Reconnect => {
+                        let router = &mut self.routers[index];
+                        let reconnect_result = router.handle_reconnect().await;
+                        router.reconnect.update_next_time(reconnect_result.is_err());
+                    },
+                    ConnectionEvent::Received(Ok(envelope_down_v1::Data::Packet(message))) =>
+                        self.handle_downlink(message).await,
+                    ConnectionEvent::Received(Ok(envelope_down_v1::Data::SessionOffer(message))) => {
+                        let session_result = self.handle_session_offer(index, message).await;
+                        let router = &mut self.routers[index];
                         if session_result.is_ok() {
                             // (Re)set retry count to max to maximize time to
                             // next disconnect from service
-                            self.reconnect.retry_count = self.reconnect.max_retries;
+                            router.reconnect.retry_count = router.reconnect.max_retries;
                         } else {
                             // Failed fto handle session offer, disconnect
-                            self.service.disconnect();
+                            router.service.disconnect();
                         }
-                        self.reconnect.update_next_time(session_result.is_err());
+                        router.reconnect.update_next_time(session_result.is_err());
                     },
-                    Err(err) => {
-                        warn!(?err, "router error");
-                        self.reconnect.update_next_time(true);
+                    ConnectionEvent::Received(Err(err)) => {
+                        let router = &mut self.routers[index];
+                        warn!(uri = %router.service.uri, ?err, "router error");
+                        router.reconnect.update_next_time(true);
                     },
                 }
             }
         }
     }
 
-    async fn handle_reconnect(&mut self) -> Result {
-        // Do not send waiting packets on ok here since we wait for a session
-        // offer. Also do not reset the reconnect retry counter since only a
-        // session key indicates a good connection
-        self.service
-            .reconnect()
-            .inspect_err(|err| warn!(%err, "failed to reconnect"))
-            .await
+    /// Returns the status of the router uplinks are currently sent to. That
+    /// is the first connected router, or the primary router if none is
+    /// connected.
+    fn status(&self) -> RouterStatus {
+        let router = self
+            .routers
+            .iter()
+            .find(|router| router.service.is_connected())
+            .unwrap_or(&self.routers[0]);
+        RouterStatus {
+            uri: router.service.uri.clone(),
+            connected: router.service.is_connected(),
+            session_key: router.service.session_key().cloned(),
+        }
     }
 
-    async fn handle_uplink(&mut self, uplink: PacketUp, received: StdInstant) -> Result {
-        self.store.push_back(uplink, received);
-        if self.service.is_connected() {
-            self.send_waiting_packets().await?;
+    async fn handle_uplink(&mut self, uplink: PacketUp, received: StdInstant) {
+        let indices = match self.mode {
+            RouterMode::Failover => {
+                // Queue on the primary router while no router is connected
+                let index = self
+                    .routers
+                    .iter()
+                    .position(|router| router.service.is_connected())
+                    .unwrap_or(0);
+                vec![index]
+            }
+            RouterMode::Mirror => (0..self.routers.len()).collect(),
+        };
+        for index in indices {
+            let router = &mut self.routers[index];
+            router.store.push_back(uplink.clone(), received);
+            if router.service.is_connected() && self.send_waiting_packets(index).await.is_err() {
+                let router = &mut self.routers[index];
+                router.service.disconnect();
+                warn!(uri = %router.service.uri, "router disconnected");
+                router.reconnect.update_next_time(true);
+            }
         }
-        Ok(())
     }
 
     async fn handle_downlink(&mut self, message: PacketRouterPacketDownV1) {
         self.transmit.downlink(message.into()).await;
     }
 
-    async fn handle_session_offer(&mut self, message: PacketRouterSessionOfferV1) -> Result {
-        self.service.session_init(&message.nonce).await?;
-        self.send_waiting_packets()
+    async fn handle_session_offer(
+        &mut self,
+        index: usize,
+        message: PacketRouterSessionOfferV1,
+    ) -> Result {
+        self.routers[index]
+            .service
+            .session_init(&message.nonce)
+            .await?;
+        self.send_waiting_packets(index)
             .inspect_err(|err| warn!(%err, "failed to send queued packets"))
             .await
     }
 
-    async fn send_waiting_packets(&mut self) -> Result {
-        while let (removed, Some(packet)) = self.store.pop_front(STORE_GC_INTERVAL) {
+    async fn send_waiting_packets(&mut self, index: usize) -> Result {
+        while let (removed, Some(packet)) = self.routers[index].store.pop_front(STORE_GC_INTERVAL) {
             if removed > 0 {
                 info!(removed, "discarded queued packets");
             }
-            if let Err(err) = self.send_packet(&packet).await {
+            if let Err(err) = self.send_packet(index, &packet).await {
                 warn!(%err, "failed to send uplink");
-                self.store.push_front(packet);
+                self.routers[index].store.push_front(packet);
                 return Err(err);
             }
         }
         Ok(())
     }
 
-    async fn send_packet(&mut self, packet: &CacheMessage<PacketUp>) -> Result {
+    async fn send_packet(&mut self, index: usize, packet: &CacheMessage<PacketUp>) -> Result {
         debug!(packet_hash = packet.hash().to_b64(), "sending packet");
 
+        let hold_time = packet.hold_time();
         let mut uplink: PacketRouterPacketUpV1 = packet.deref().into();
-        uplink.hold_time = packet.hold_time().as_millis() as u64;
-        self.service.send_uplink(uplink).await
+        uplink.hold_time = hold_time.as_millis() as u64;
+        let service = &mut self.routers[index].service;
+        service.send_uplink(uplink).await?;
+        self.audit
+            .record(AuditRecord::uplink(packet, &service.uri, hold_time));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        packet::test::DATA_FRAME,
+        service::{
+            conduit::KeepAlive,
+            packet_router::test::{BlackholeProxy, TestRouter},
+        },
+        Keypair,
+    };
+    use std::sync::Arc;
+
+    fn mk_packet_router(
+        uris: Vec<http::Uri>,
+        mode: RouterMode,
+        keepalive: Option<KeepAlive>,
+        audit: AuditLog,
+    ) -> (PacketRouter, MessageSender, gateway::MessageReceiver) {
+        let (messages_tx, messages) = message_channel();
+        let (transmit, transmit_rx) = gateway::message_channel();
+        let keypair = Arc::new(Keypair::new());
+        let routers = uris
+            .into_iter()
+            .map(|uri| RouterConnection {
+                service: PacketRouterService::new(uri, keypair.clone(), keepalive),
+                // Connect soon after start, and only reconnect early on
+                // failures since the maximum wait applies once a session is up
+                reconnect: Reconnect::new(1, Duration::from_secs(1), Duration::from_secs(60)),
+                store: MessageCache::new(20),
+            })
+            .collect();
+        let router = PacketRouter {
+            messages,
+            transmit,
+            mode,
+            routers,
+            audit,
+            heartbeat: Heartbeat::default(),
+        };
+        (router, messages_tx, transmit_rx)
+    }
+
+    fn mk_uplink(frequency: u32) -> PacketUp {
+        PacketUp::from(PacketRouterPacketUpV1 {
+            payload: DATA_FRAME.to_vec(),
+            frequency,
+            datarate: helium_proto::DataRate::Sf10bw125 as i32,
+            ..Default::default()
+        })
+    }
+
+    /// Returns the uri of a local port nothing listens on
+    fn dead_uri() -> http::Uri {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("listener");
+        let addr = listener.local_addr().expect("addr");
+        drop(listener);
+        format!("http://{addr}").parse().expect("dead uri")
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_timeout_reconnect() {
+        let mut test_router = TestRouter::start().await;
+        let proxy = BlackholeProxy::start(test_router.addr).await;
+        let (mut router, _messages, _transmit) = mk_packet_router(
+            vec![proxy.uri()],
+            RouterMode::Failover,
+            Some(KeepAlive {
+                interval: Duration::from_millis(200),
+                timeout: Duration::from_secs(1),
+            }),
+            AuditLog::default(),
+        );
+        let (trigger, shutdown) = triggered::trigger();
+
+        let test = async {
+            test_router.next_session().await;
+
+            // Unacknowledged keepalive pings close the half-open connection.
+            // The router sees that as a receive error and reconnects, which
+            // establishes a new session through a new proxy connection
+            test_router.clear_events();
+            proxy.blackhole();
+            test_router.next_session().await;
+
+            trigger.trigger();
+        };
+        let (result, _) = tokio::join!(router.run(&shutdown), test);
+        result.expect("router run");
+    }
+
+    #[tokio::test]
+    async fn test_send_packet_audit() {
+        let dir = std::env::temp_dir().join(format!("router_audit_{}", std::process::id()));
+        let (audit, guard) = AuditLog::with_dir(&dir).expect("audit log");
+        let mut test_router = TestRouter::start().await;
+        let (mut router, messages, _transmit) =
+            mk_packet_router(vec![test_router.uri()], RouterMode::Failover, None, audit);
+        let (trigger, shutdown) = triggered::trigger();
+
+        let test = async {
+            test_router.next_session().await;
+            messages
+                .uplink(mk_uplink(904_300_000), StdInstant::now())
+                .await;
+            // The uplink is recorded once it is handed to the router
+            // connection, before it reaches the test router
+            let received = test_router.next_uplink().await;
+            assert_eq!(DATA_FRAME.to_vec(), received.payload);
+            trigger.trigger();
+        };
+        let (result, _) = tokio::join!(router.run(&shutdown), test);
+        result.expect("router run");
+
+        // Dropping the guard flushes the outstanding records
+        drop(router);
+        drop(guard);
+        let records = crate::audit::test::read_records(&dir);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(1, records.len());
+        assert_eq!("uplink", records[0]["type"]);
+        assert_eq!("01020304", records[0]["devaddr"]);
+        assert_eq!(test_router.uri().to_string(), records[0]["router"]);
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        // The secondary router is used while the primary is down
+        let mut secondary = TestRouter::start().await;
+        let (mut router, messages, _transmit) = mk_packet_router(
+            vec![dead_uri(), secondary.uri()],
+            RouterMode::Failover,
+            None,
+            AuditLog::default(),
+        );
+        let (trigger, shutdown) = triggered::trigger();
+        let test = async {
+            secondary.next_session().await;
+            messages
+                .uplink(mk_uplink(904_300_000), StdInstant::now())
+                .await;
+            assert_eq!(904_300_000, secondary.next_uplink().await.frequency);
+            trigger.trigger();
+        };
+        let (result, _) = tokio::join!(router.run(&shutdown), test);
+        result.expect("router run");
+
+        // Only the primary router is used while it is up
+        let mut primary = TestRouter::start().await;
+        let mut secondary = TestRouter::start().await;
+        let (mut router, messages, _transmit) = mk_packet_router(
+            vec![primary.uri(), secondary.uri()],
+            RouterMode::Failover,
+            None,
+            AuditLog::default(),
+        );
+        let (trigger, shutdown) = triggered::trigger();
+        let test = async {
+            primary.next_session().await;
+            secondary.next_session().await;
+            for frequency in [904_300_000, 904_500_000] {
+                messages
+                    .uplink(mk_uplink(frequency), StdInstant::now())
+                    .await;
+                assert_eq!(frequency, primary.next_uplink().await.frequency);
+            }
+            assert!(secondary.try_next_uplink().is_none());
+            trigger.trigger();
+        };
+        let (result, _) = tokio::join!(router.run(&shutdown), test);
+        result.expect("router run");
+    }
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let mut primary = TestRouter::start().await;
+        let mut secondary = TestRouter::start().await;
+        let (mut router, messages, _transmit) = mk_packet_router(
+            vec![primary.uri(), secondary.uri()],
+            RouterMode::Mirror,
+            None,
+            AuditLog::default(),
+        );
+        let (trigger, shutdown) = triggered::trigger();
+        let test = async {
+            primary.next_session().await;
+            secondary.next_session().await;
+            for frequency in [904_300_000, 904_500_000] {
+                messages
+                    .uplink(mk_uplink(frequency), StdInstant::now())
+                    .await;
+                assert_eq!(frequency, primary.next_uplink().await.frequency);
+                assert_eq!(frequency, secondary.next_uplink().await.frequency);
+            }
+            trigger.trigger();
+        };
+        let (result, _) = tokio::join!(router.run(&shutdown), test);
+        result.expect("router run");
     }
 }
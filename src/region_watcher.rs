@@ -1,6 +1,15 @@
-use crate::{settings::Settings, KeyedUri, Keypair, Region, RegionParams, Result};
+use crate::{
+    settings::Settings, watchdog::Heartbeat, Error, KeyedUri, Keypair, PublicKey, Region,
+    RegionParams, Result, Verify,
+};
 use exponential_backoff::Backoff;
-use std::{sync::Arc, time::Duration};
+use helium_proto::{services::iot_config::GatewayRegionParamsResV1, Message};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{sync::watch, time};
 use tracing::{info, warn};
 
@@ -8,6 +17,8 @@ const REGION_BACKOFF_RETRIES: u32 = 10;
 const REGION_BACKOFF_MIN_WAIT: Duration = Duration::from_secs(5);
 const REGION_BACKOFF_MAX_WAIT: Duration = Duration::from_secs(3600); // 60 minutes
 
+const REGION_PARAMS_CACHE_FILE: &str = "region_params.bin";
+
 pub type MessageSender = watch::Sender<RegionParams>;
 pub type MessageReceiver = watch::Receiver<RegionParams>;
 
@@ -24,11 +35,39 @@ pub struct RegionWatcher {
     default_region: Region,
     request_retry: u32,
     watch: MessageSender,
+    cache: Option<RegionParamsCache>,
+    /// Timestamp of the last region params fetched from the config service
+    /// since startup. Params loaded from the cache do not count, so a bad
+    /// cached timestamp can not block later fetches.
+    fetched_timestamp: Option<u64>,
+    heartbeat: Heartbeat,
 }
 
 impl RegionWatcher {
     pub fn new(settings: &Settings) -> Self {
-        let default_params = RegionParams::from(settings.region);
+        let cache = settings
+            .cache
+            .as_deref()
+            .map(|dir| RegionParamsCache::new(dir, settings.config.pubkey.clone()));
+        // Start out with the last known region params if available so the
+        // gateway can operate before the config service is reachable
+        let default_params = cache
+            .as_ref()
+            .and_then(|cache| match cache.load() {
+                Ok(params) => {
+                    info!(region = %params.region, "using cached region_params");
+                    Some(params)
+                }
+                Err(Error::IO(err)) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => {
+                    warn!(%err, "discarding cached region_params");
+                    if let Err(err) = cache.discard() {
+                        warn!(%err, "failed to discard cached region_params");
+                    }
+                    None
+                }
+            })
+            .unwrap_or_else(|| RegionParams::from(settings.region));
         let (watch, _) = watch::channel(default_params);
         Self {
             keypair: settings.keypair.clone(),
@@ -37,9 +76,17 @@ impl RegionWatcher {
             request_retry: 1,
             default_region: settings.region,
             watch,
+            cache,
+            fetched_timestamp: None,
+            heartbeat: Heartbeat::default(),
         }
     }
 
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
     pub fn watcher(&mut self) -> watch::Receiver<RegionParams> {
         self.watch.subscribe()
     }
@@ -56,32 +103,36 @@ impl RegionWatcher {
             REGION_BACKOFF_MAX_WAIT,
         );
 
+        let next_check = |request_retry| {
+            time::Instant::now()
+                + backoff
+                    .next(request_retry)
+                    .unwrap_or(REGION_BACKOFF_MAX_WAIT)
+        };
+        // Keep the check deadline across loop iterations so heartbeats do
+        // not postpone it
+        let mut check_deadline = next_check(self.request_retry);
         loop {
-            let sleep = backoff
-                .next(self.request_retry)
-                .unwrap_or(REGION_BACKOFF_MAX_WAIT);
-
             tokio::select! {
                 _ = shutdown.clone() => {
                     info!("shutting down");
                     return Ok(())
                 },
-                _ = time::sleep(sleep) => match self.check_region(shutdown).await {
-                    // A successful fetch will set request_retry to RETRIES + 1
-                    // which means a first error can reset it back to 1 to start
-                    // backing of up to RETRIES
-                    Err(_) => self.request_retry = if self.request_retry > REGION_BACKOFF_RETRIES {
-                        1
-                    } else {
-                        (self.request_retry + 1).min(REGION_BACKOFF_RETRIES)
-                    },
-                    Ok(None) => (),
-                    Ok(Some(remote_params)) => {
-                        self.request_retry = REGION_BACKOFF_RETRIES + 1;
-                        // We do not check for a change in params here since we
-                        // want to propagate the timestamp in the remote params
-                        _ = self.watch.send_replace(remote_params);
-                    },
+                _ = self.heartbeat.tick() => (),
+                _ = time::sleep_until(check_deadline) => {
+                    match self.check_region(shutdown).await {
+                        // A successful fetch will set request_retry to RETRIES + 1
+                        // which means a first error can reset it back to 1 to start
+                        // backing of up to RETRIES
+                        Err(_) => self.request_retry = if self.request_retry > REGION_BACKOFF_RETRIES {
+                            1
+                        } else {
+                            (self.request_retry + 1).min(REGION_BACKOFF_RETRIES)
+                        },
+                        Ok(None) => (),
+                        Ok(Some(_)) => self.request_retry = REGION_BACKOFF_RETRIES + 1,
+                    }
+                    check_deadline = next_check(self.request_retry);
                 }
             }
         }
@@ -97,7 +148,7 @@ impl RegionWatcher {
 
         tokio::select! {
             _ = shutdown.clone() => Ok(None),
-            response = service.region_params(current_region, self.keypair.clone()) => match response.map(Some) {
+            response = service.region_params_res(current_region, self.keypair.clone()) => match response.and_then(|resp| self.apply_region_params(resp)).map(Some) {
                 Err(err) => {
                     warn!(
                         pubkey = %service_uri.pubkey,
@@ -122,4 +173,358 @@ impl RegionWatcher {
         }
         }
     }
+
+    /// Applies a verified region params response from the config service. A
+    /// response that can not be converted to region params or that fails
+    /// validation results in an error and leaves the currently active region
+    /// params in place. Otherwise the response is cached and its params are
+    /// published, even if they are empty, since that is how the config
+    /// service reports that there are no region params for this gateway.
+    /// Responses that are not newer than the last fetched params are out of
+    /// order or duplicates and are neither cached nor published. Params loaded
+    /// from the cache on startup are replaced by the first valid fetch
+    /// regardless of their timestamp.
+    fn apply_region_params(&mut self, resp: GatewayRegionParamsResV1) -> Result<RegionParams> {
+        let params = RegionParams::try_from(resp.clone())?;
+        validate_region_params(&resp, &params)?;
+        if !is_newer_region_params(self.fetched_timestamp, &params) {
+            warn!(timestamp = params.timestamp, "ignoring stale region_params");
+            return Ok(params);
+        }
+        self.fetched_timestamp = Some(params.timestamp);
+        if let Some(cache) = self.cache.as_ref() {
+            if let Err(err) = cache.save(&resp) {
+                warn!(%err, "failed to cache region_params");
+            }
+        }
+        // We do not check for a change in params here since we want to
+        // propagate the timestamp in the remote params
+        _ = self.watch.send_replace(params.clone());
+        Ok(params)
+    }
+}
+
+/// Validates fetched region params before they replace the active params. A
+/// response without channels is the config service's answer that there are no
+/// region params for this gateway and is accepted as is. A response with
+/// channels must be for a known region, and every channel needs a frequency,
+/// a bandwidth, a spreading and a usable transmit power.
+fn validate_region_params(resp: &GatewayRegionParamsResV1, params: &RegionParams) -> Result {
+    let channels = resp
+        .params
+        .as_ref()
+        .map(|params| params.region_params.as_slice())
+        .unwrap_or_default();
+    if channels.is_empty() {
+        return Ok(());
+    }
+    params.check_valid()?;
+    if let Some(channel) = channels.iter().find(|channel| {
+        channel.channel_frequency == 0
+            || channel.bandwidth == 0
+            || channel.max_eirp == 0
+            || !channel
+                .spreading
+                .as_ref()
+                .is_some_and(|spreading| !spreading.tagged_spreading.is_empty())
+    }) {
+        return Err(Error::custom(format!(
+            "invalid region_params channel {}",
+            channel.channel_frequency
+        )));
+    }
+    params.max_conducted_power()?;
+    Ok(())
+}
+
+fn is_newer_region_params(fetched_timestamp: Option<u64>, new: &RegionParams) -> bool {
+    match fetched_timestamp {
+        Some(timestamp) => new.timestamp > timestamp,
+        None => true,
+    }
+}
+
+/// A disk cache for the last fetched region parameters. The signed config
+/// service response is stored as is so that it can be verified against the
+/// config service key when loaded again.
+#[derive(Debug)]
+pub struct RegionParamsCache {
+    path: PathBuf,
+    pubkey: Arc<PublicKey>,
+}
+
+impl RegionParamsCache {
+    pub fn new(dir: &Path, pubkey: Arc<PublicKey>) -> Self {
+        Self {
+            path: dir.join(REGION_PARAMS_CACHE_FILE),
+            pubkey,
+        }
+    }
+
+    pub fn load(&self) -> Result<RegionParams> {
+        Ok(RegionParams::try_from(self.load_res()?)?)
+    }
+
+    fn load_res(&self) -> Result<GatewayRegionParamsResV1> {
+        // A temporary file left behind by an interrupted save is never
+        // complete, so remove it
+        match fs::remove_file(self.tmp_path()) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                warn!(%err, "failed to remove stale region_params tmp file")
+            }
+            _ => (),
+        }
+        let data = fs::read(&self.path)?;
+        let resp = GatewayRegionParamsResV1::decode(data.as_slice())?;
+        resp.verify(&self.pubkey)?;
+        Ok(resp)
+    }
+
+    /// Saves the given response to the cache. The response is written to a
+    /// temporary file first and then moved in place to avoid leaving a
+    /// partially written cache file behind.
+    pub fn save(&self, resp: &GatewayRegionParamsResV1) -> Result {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = self.tmp_path();
+        fs::write(&tmp_path, resp.encode_to_vec())?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Removes the cache file, for example when it is found to be corrupt.
+    pub fn discard(&self) -> Result {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        self.path.with_extension("tmp")
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::{impl_sign, Sign};
+
+    impl_sign!(GatewayRegionParamsResV1);
+
+    #[tokio::test]
+    async fn test_region_params_cache_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("region_params_cache_{}", std::process::id()));
+        let keypair = Arc::new(Keypair::new());
+        let cache = RegionParamsCache::new(&dir, Arc::new(keypair.public_key().clone()));
+
+        let mut resp = mk_region_params_res(100);
+        resp.sign(keypair.clone()).await.expect("signed response");
+        cache.save(&resp).expect("saved response");
+
+        // The reloaded params have the same channels, datarates and transmit
+        // power as the fetched ones
+        let params = RegionParams::try_from(resp.clone()).expect("region params");
+        let loaded = cache.load().expect("loaded params");
+        assert_eq!(params, loaded);
+        assert_eq!(
+            params.max_conducted_power().expect("tx power"),
+            loaded.max_conducted_power().expect("loaded tx power")
+        );
+
+        // A response that does not match its signature is rejected
+        resp.gain = 80;
+        cache.save(&resp).expect("saved tampered response");
+        assert!(cache.load_res().is_err());
+
+        // A leftover partial tmp file is removed on load and a corrupt cache
+        // file can be discarded
+        fs::write(cache.tmp_path(), b"partial").expect("tmp file");
+        fs::write(&cache.path, b"corrupt").expect("corrupt file");
+        assert!(cache.load_res().is_err());
+        assert!(!cache.tmp_path().exists());
+        cache.discard().expect("discarded cache");
+        assert!(!cache.path.exists());
+        cache.discard().expect("discarded missing cache");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_newer_region_params() {
+        let mut new = RegionParams::from(Region::default());
+
+        new.timestamp = 50;
+        assert!(!is_newer_region_params(Some(100), &new));
+        new.timestamp = 100;
+        assert!(!is_newer_region_params(Some(100), &new));
+        new.timestamp = 150;
+        assert!(is_newer_region_params(Some(100), &new));
+        // Without a fetch since startup any params are accepted
+        new.timestamp = 0;
+        assert!(is_newer_region_params(None, &new));
+    }
+
+    pub(crate) fn mk_region_params_res(timestamp: u64) -> GatewayRegionParamsResV1 {
+        use helium_proto::{
+            BlockchainRegionParamV1, BlockchainRegionParamsV1, BlockchainRegionSpreadingV1,
+            RegionSpreading, TaggedSpreading,
+        };
+        let region_params = [903_900_000, 904_100_000]
+            .into_iter()
+            .map(|channel_frequency| BlockchainRegionParamV1 {
+                channel_frequency,
+                bandwidth: 125_000,
+                max_eirp: 360,
+                spreading: Some(BlockchainRegionSpreadingV1 {
+                    tagged_spreading: vec![TaggedSpreading {
+                        region_spreading: RegionSpreading::Sf10 as i32,
+                        max_packet_size: 24,
+                    }],
+                }),
+            })
+            .collect();
+        GatewayRegionParamsResV1 {
+            region: helium_proto::Region::Us915 as i32,
+            params: Some(BlockchainRegionParamsV1 { region_params }),
+            gain: 12,
+            timestamp,
+            ..Default::default()
+        }
+    }
+
+    fn mk_region_watcher(keypair: Arc<Keypair>, cache_dir: &Path) -> RegionWatcher {
+        let pubkey = Arc::new(keypair.public_key().clone());
+        let (watch, _) = watch::channel(RegionParams::from(Region::default()));
+        RegionWatcher {
+            keypair,
+            config_uri: KeyedUri {
+                uri: http::Uri::from_static("http://127.0.0.1:8080"),
+                pubkey: pubkey.clone(),
+            },
+            default_region: Region::default(),
+            request_retry: 1,
+            watch,
+            cache: Some(RegionParamsCache::new(cache_dir, pubkey)),
+            fetched_timestamp: None,
+            heartbeat: Heartbeat::default(),
+        }
+    }
+
+    fn cached_value(watcher: &RegionWatcher) -> RegionParams {
+        let cache = watcher.cache.as_ref().expect("cache");
+        cache.load().expect("cached params")
+    }
+
+    #[tokio::test]
+    async fn test_apply_region_params() {
+        let dir = std::env::temp_dir().join(format!("region_params_apply_{}", std::process::id()));
+        let keypair = Arc::new(Keypair::new());
+        let mut watcher = mk_region_watcher(keypair.clone(), &dir);
+        let region_watch = watcher.watcher();
+
+        let mut resp = mk_region_params_res(100);
+        resp.sign(keypair.clone()).await.expect("signed response");
+        let params = watcher.apply_region_params(resp).expect("applied params");
+        assert_eq!(params, current_value(&region_watch));
+
+        // A malformed response is rejected and leaves the active params and
+        // the cache in place
+        let mut malformed = mk_region_params_res(200);
+        malformed.region = 9999;
+        malformed
+            .sign(keypair.clone())
+            .await
+            .expect("signed response");
+        assert!(watcher.apply_region_params(malformed).is_err());
+        assert_eq!(params, current_value(&region_watch));
+        assert_eq!(params, cached_value(&watcher));
+
+        // A response with channels that fail validation is rejected and
+        // leaves the active params and the cache in place
+        let mut invalid = mk_region_params_res(250);
+        if let Some(invalid_params) = invalid.params.as_mut() {
+            invalid_params.region_params[0].max_eirp = 0;
+        }
+        invalid
+            .sign(keypair.clone())
+            .await
+            .expect("signed response");
+        assert!(watcher.apply_region_params(invalid).is_err());
+        assert_eq!(params, current_value(&region_watch));
+        assert_eq!(params, cached_value(&watcher));
+
+        // A well formed response without region params is published and
+        // cached
+        let mut empty = GatewayRegionParamsResV1 {
+            timestamp: 300,
+            ..Default::default()
+        };
+        empty.sign(keypair.clone()).await.expect("signed response");
+        let empty_params = watcher.apply_region_params(empty).expect("applied params");
+        assert!(empty_params.check_valid().is_err());
+        assert_eq!(empty_params, current_value(&region_watch));
+        assert_eq!(empty_params, cached_value(&watcher));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_apply_stale_region_params() {
+        let dir = std::env::temp_dir().join(format!("region_params_stale_{}", std::process::id()));
+        let keypair = Arc::new(Keypair::new());
+        let mut watcher = mk_region_watcher(keypair.clone(), &dir);
+        let region_watch = watcher.watcher();
+
+        let mut resp = mk_region_params_res(100);
+        resp.sign(keypair.clone()).await.expect("signed response");
+        let params = watcher.apply_region_params(resp).expect("applied params");
+
+        // Out of order and duplicate responses are neither published nor
+        // cached
+        for timestamp in [50, 100] {
+            let mut stale = mk_region_params_res(timestamp);
+            stale.gain = 80;
+            stale.sign(keypair.clone()).await.expect("signed response");
+            watcher.apply_region_params(stale).expect("ignored params");
+            assert_eq!(params, current_value(&region_watch));
+            assert_eq!(params, cached_value(&watcher));
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_apply_region_params_after_future_cache() {
+        let dir = std::env::temp_dir().join(format!("region_params_future_{}", std::process::id()));
+        let keypair = Arc::new(Keypair::new());
+        let mut watcher = mk_region_watcher(keypair.clone(), &dir);
+        let region_watch = watcher.watcher();
+
+        // Start out with cached params that carry a timestamp far in the
+        // future, for example from a config service with a bad clock
+        let mut future = mk_region_params_res(u64::MAX / 2);
+        future.sign(keypair.clone()).await.expect("signed response");
+        let cache = watcher.cache.as_ref().expect("cache");
+        cache.save(&future).expect("saved response");
+        let cached = cache.load().expect("cached params");
+        watcher.watch.send_replace(cached);
+
+        // The first fetch replaces the cached params despite its older
+        // timestamp, and becomes the baseline for later fetches
+        let mut resp = mk_region_params_res(100);
+        resp.gain = 20;
+        resp.sign(keypair.clone()).await.expect("signed response");
+        let params = watcher.apply_region_params(resp).expect("applied params");
+        assert_eq!(params, current_value(&region_watch));
+        assert_eq!(params, cached_value(&watcher));
+
+        let mut stale = mk_region_params_res(50);
+        stale.sign(keypair.clone()).await.expect("signed response");
+        watcher.apply_region_params(stale).expect("ignored params");
+        assert_eq!(params, current_value(&region_watch));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -1,7 +1,10 @@
 use crate::{
+    animal_name,
     api::LocalServer,
+    audit::AuditLog,
     beaconer, gateway, packet_router, region_watcher,
     settings::{self, Settings},
+    watchdog::Watchdog,
     Result,
 };
 use tracing::info;
@@ -11,14 +14,22 @@ pub async fn run(shutdown: &triggered::Listener, settings: &Settings) -> Result
     let (gateway_tx, gateway_rx) = gateway::message_channel();
     let (router_tx, router_rx) = packet_router::message_channel();
     let (beacon_tx, beacon_rx) = beaconer::message_channel();
+    // Hold on to the audit guard to flush outstanding audit records on exit
+    let (audit, _audit_guard) = AuditLog::new(settings)?;
+    settings.init_cache_dir()?;
+    let mut watchdog = Watchdog::new(&settings.watchdog);
 
-    let mut region_watcher = region_watcher::RegionWatcher::new(settings);
+    let mut region_watcher = region_watcher::RegionWatcher::new(settings)
+        .with_heartbeat(watchdog.heartbeat("region_watcher"));
     let region_rx = region_watcher.watcher();
 
     let mut beaconer =
-        beaconer::Beaconer::new(settings, beacon_rx, region_rx.clone(), gateway_tx.clone());
+        beaconer::Beaconer::new(settings, beacon_rx, region_rx.clone(), gateway_tx.clone())
+            .with_heartbeat(watchdog.heartbeat("beaconer"));
 
-    let mut router = packet_router::PacketRouter::new(settings, router_rx, gateway_tx.clone());
+    let mut router =
+        packet_router::PacketRouter::new(settings, router_rx, gateway_tx.clone(), audit.clone())
+            .with_heartbeat(watchdog.heartbeat("router"));
 
     let mut gateway = gateway::Gateway::new(
         settings,
@@ -26,12 +37,16 @@ pub async fn run(shutdown: &triggered::Listener, settings: &Settings) -> Result
         region_rx.clone(),
         router_tx.clone(),
         beacon_tx,
+        audit,
     )
-    .await?;
-    let api = LocalServer::new(region_rx.clone(), router_tx.clone(), settings)?;
+    .await?
+    .with_heartbeat(watchdog.heartbeat("gateway"));
+    let api = LocalServer::new(region_rx.clone(), router_tx.clone(), settings)?
+        .with_heartbeat(watchdog.heartbeat("api"));
     info!(
         version = %settings::version().to_string(),
         key = %settings.keypair.public_key().to_string(),
+        name = animal_name(settings.keypair.public_key()),
         "starting server",
     );
     tokio::try_join!(
@@ -40,6 +55,7 @@ pub async fn run(shutdown: &triggered::Listener, settings: &Settings) -> Result
         gateway.run(shutdown),
         router.run(shutdown),
         api.run(shutdown),
+        watchdog.run(shutdown),
     )
     .map(|_| ())
 }
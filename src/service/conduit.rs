@@ -17,6 +17,18 @@ use tracing::{info, warn};
 pub const TCP_KEEP_ALIVE_DURATION: std::time::Duration = std::time::Duration::from_secs(300);
 pub const CONDUIT_CAPACITY: usize = 50;
 
+/// HTTP/2 keepalive configuration for a conduit. When configured the conduit
+/// sends HTTP/2 pings at the given interval and closes the connection when a
+/// ping is not acknowledged within the timeout. This detects half-open
+/// connections (for example after a NAT timeout) which would otherwise
+/// silently drop messages. A closed connection surfaces as a receive error
+/// which disconnects the conduit and triggers a reconnect by the owner.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    pub interval: std::time::Duration,
+    pub timeout: std::time::Duration,
+}
+
 /// A conduit service maintains a re-connectable connection to a remote service.
 #[derive(Debug)]
 pub struct ConduitService<U, D, C: ConduitClient<U, D>> {
@@ -25,6 +37,7 @@ pub struct ConduitService<U, D, C: ConduitClient<U, D>> {
     session_keypair: Option<Arc<Keypair>>,
     conduit: Option<Conduit<U, D>>,
     keypair: Arc<Keypair>,
+    keepalive: Option<KeepAlive>,
     client: C,
 }
 
@@ -57,12 +70,19 @@ impl<U, D> Conduit<U, D> {
         uri: Uri,
         client: &mut C,
         keypair: Arc<Keypair>,
+        keepalive: Option<KeepAlive>,
     ) -> Result<Self> {
-        let endpoint = Endpoint::from(uri)
+        let mut endpoint = Endpoint::from(uri)
             .timeout(RPC_TIMEOUT)
             .connect_timeout(CONNECT_TIMEOUT)
-            .tcp_keepalive(Some(TCP_KEEP_ALIVE_DURATION))
-            .connect_lazy();
+            .tcp_keepalive(Some(TCP_KEEP_ALIVE_DURATION));
+        if let Some(keepalive) = keepalive {
+            endpoint = endpoint
+                .http2_keep_alive_interval(keepalive.interval)
+                .keep_alive_timeout(keepalive.timeout)
+                .keep_alive_while_idle(true);
+        }
+        let endpoint = endpoint.connect_lazy();
         let (tx, client_rx) = mpsc::channel(CONDUIT_CAPACITY);
         let rx = client
             .init(
@@ -93,9 +113,17 @@ impl<U, D, C: ConduitClient<U, D>> ConduitService<U, D, C> {
             client,
             conduit: None,
             session_keypair: None,
+            keepalive: None,
         }
     }
 
+    /// Sets the HTTP/2 keepalive configuration for connections made by this
+    /// conduit service. Keepalive pings are disabled when None.
+    pub fn with_keepalive(mut self, keepalive: Option<KeepAlive>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
     pub async fn send(&mut self, msg: U) -> Result {
         if self.conduit.is_none() {
             self.connect().await?;
@@ -139,8 +167,13 @@ impl<U, D, C: ConduitClient<U, D>> ConduitService<U, D, C> {
     }
 
     pub async fn connect(&mut self) -> Result {
-        let conduit =
-            Conduit::new(self.uri.clone(), &mut self.client, self.keypair.clone()).await?;
+        let conduit = Conduit::new(
+            self.uri.clone(),
+            &mut self.client,
+            self.keypair.clone(),
+            self.keepalive,
+        )
+        .await?;
         self.conduit = Some(conduit);
         Ok(())
     }
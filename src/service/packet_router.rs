@@ -1,6 +1,6 @@
 use crate::{
     impl_sign,
-    service::conduit::{ConduitClient, ConduitService},
+    service::conduit::{ConduitClient, ConduitService, KeepAlive},
     DecodeError, Error, Keypair, PublicKey, Result, Sign,
 };
 use helium_proto::{
@@ -98,9 +98,9 @@ impl std::ops::DerefMut for PacketRouterService {
 }
 
 impl PacketRouterService {
-    pub fn new(uri: Uri, keypair: Arc<Keypair>) -> Self {
+    pub fn new(uri: Uri, keypair: Arc<Keypair>, keepalive: Option<KeepAlive>) -> Self {
         let client = PacketRouterConduitClient {};
-        Self(ConduitService::new("packet_router", uri, client, keypair))
+        Self(ConduitService::new("packet_router", uri, client, keypair).with_keepalive(keepalive))
     }
 
     pub async fn send_uplink(&mut self, mut msg: PacketRouterPacketUpV1) -> Result {
@@ -118,3 +118,240 @@ impl PacketRouterService {
         })
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use helium_proto::services::router::{
+        envelope_down_v1, envelope_up_v1,
+        packet_router_server::{PacketRouter, PacketRouterServer},
+        EnvelopeDownV1, EnvelopeUpV1, PacketRouterPacketUpV1, PacketRouterSessionOfferV1,
+    };
+    use http::Uri;
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+        task::{JoinHandle, JoinSet},
+    };
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::transport::{server::TcpIncoming, Server};
+
+    /// Upper bound for waiting on an expected router event
+    pub(crate) const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[derive(Debug)]
+    pub(crate) enum RouterEvent {
+        /// A gateway opened a route stream
+        Connected,
+        /// A gateway accepted the session offer
+        SessionInit,
+        Uplink(PacketRouterPacketUpV1),
+    }
+
+    /// A packet router server on a local port. It offers a session on every
+    /// route stream and reports connections, sessions and uplinks as events.
+    /// The server is stopped when dropped.
+    pub(crate) struct TestRouter {
+        pub addr: SocketAddr,
+        events: mpsc::UnboundedReceiver<RouterEvent>,
+        server: JoinHandle<()>,
+    }
+
+    struct TestRouterService {
+        events: mpsc::UnboundedSender<RouterEvent>,
+    }
+
+    #[tonic::async_trait]
+    impl PacketRouter for TestRouterService {
+        type RouteStream = ReceiverStream<std::result::Result<EnvelopeDownV1, tonic::Status>>;
+
+        async fn route(
+            &self,
+            request: tonic::Request<tonic::Streaming<EnvelopeUpV1>>,
+        ) -> std::result::Result<tonic::Response<Self::RouteStream>, tonic::Status> {
+            let _ = self.events.send(RouterEvent::Connected);
+            let mut uplinks = request.into_inner();
+            let (tx, rx) = mpsc::channel(1);
+            let events = self.events.clone();
+            // Keep the downlink stream open for as long as the uplink stream is
+            tokio::spawn(async move {
+                let offer = EnvelopeDownV1 {
+                    data: Some(envelope_down_v1::Data::SessionOffer(
+                        PacketRouterSessionOfferV1 {
+                            nonce: vec![1, 2, 3, 4],
+                        },
+                    )),
+                };
+                let _ = tx.send(Ok(offer)).await;
+                while let Ok(Some(envelope)) = uplinks.message().await {
+                    let event = match envelope.data {
+                        Some(envelope_up_v1::Data::SessionInit(_)) => RouterEvent::SessionInit,
+                        Some(envelope_up_v1::Data::Packet(packet)) => RouterEvent::Uplink(packet),
+                        _ => continue,
+                    };
+                    let _ = events.send(event);
+                }
+                drop(tx);
+            });
+            Ok(tonic::Response::new(ReceiverStream::new(rx)))
+        }
+    }
+
+    impl TestRouter {
+        pub(crate) async fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("router listener");
+            let addr = listener.local_addr().expect("router addr");
+            let incoming = TcpIncoming::from_listener(listener, true, None).expect("incoming");
+            let (events_tx, events) = mpsc::unbounded_channel();
+            let service = TestRouterService { events: events_tx };
+            let server = tokio::spawn(async move {
+                let _ = Server::builder()
+                    .add_service(PacketRouterServer::new(service))
+                    .serve_with_incoming(incoming)
+                    .await;
+            });
+            Self {
+                addr,
+                events,
+                server,
+            }
+        }
+
+        pub(crate) fn uri(&self) -> Uri {
+            format!("http://{}", self.addr).parse().expect("router uri")
+        }
+
+        pub(crate) async fn next_event(&mut self) -> RouterEvent {
+            tokio::time::timeout(EVENT_TIMEOUT, self.events.recv())
+                .await
+                .expect("router event in time")
+                .expect("router event")
+        }
+
+        /// Waits for the next uplink, skipping connection and session events
+        pub(crate) async fn next_uplink(&mut self) -> PacketRouterPacketUpV1 {
+            loop {
+                if let RouterEvent::Uplink(packet) = self.next_event().await {
+                    return packet;
+                }
+            }
+        }
+
+        /// Returns an uplink received so far without waiting, skipping other
+        /// events
+        pub(crate) fn try_next_uplink(&mut self) -> Option<PacketRouterPacketUpV1> {
+            while let Ok(event) = self.events.try_recv() {
+                if let RouterEvent::Uplink(packet) = event {
+                    return Some(packet);
+                }
+            }
+            None
+        }
+
+        /// Waits for the next session, skipping other events
+        pub(crate) async fn next_session(&mut self) {
+            while !matches!(self.next_event().await, RouterEvent::SessionInit) {}
+        }
+
+        /// Discards events received so far
+        pub(crate) fn clear_events(&mut self) {
+            while self.events.try_recv().is_ok() {}
+        }
+    }
+
+    impl Drop for TestRouter {
+        fn drop(&mut self) {
+            self.server.abort();
+        }
+    }
+
+    /// A TCP proxy which can be told to silently drop all traffic on its
+    /// current connections. This simulates a half-open connection, for
+    /// example after a NAT timeout. Connections made after that are
+    /// forwarded again. All proxy tasks are stopped when dropped.
+    pub(crate) struct BlackholeProxy {
+        pub addr: SocketAddr,
+        connections: Arc<Mutex<Vec<Arc<AtomicBool>>>>,
+        task: JoinHandle<()>,
+    }
+
+    impl BlackholeProxy {
+        pub(crate) async fn start(target: SocketAddr) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0")
+                .await
+                .expect("proxy listener");
+            let addr = listener.local_addr().expect("proxy addr");
+            let connections = Arc::new(Mutex::new(Vec::new()));
+            let proxy_connections = connections.clone();
+            let task = tokio::spawn(async move {
+                // Dropping the set when this task is aborted aborts the
+                // forwarding tasks
+                let mut forwards = JoinSet::new();
+                while let Ok((client, _)) = listener.accept().await {
+                    let Ok(server) = TcpStream::connect(target).await else {
+                        continue;
+                    };
+                    let dropped = Arc::new(AtomicBool::new(false));
+                    proxy_connections
+                        .lock()
+                        .expect("proxy connections")
+                        .push(dropped.clone());
+                    let (client_rx, client_tx) = client.into_split();
+                    let (server_rx, server_tx) = server.into_split();
+                    forwards.spawn(Self::forward(client_rx, server_tx, dropped.clone()));
+                    forwards.spawn(Self::forward(server_rx, client_tx, dropped));
+                }
+            });
+            Self {
+                addr,
+                connections,
+                task,
+            }
+        }
+
+        pub(crate) fn uri(&self) -> Uri {
+            format!("http://{}", self.addr).parse().expect("proxy uri")
+        }
+
+        async fn forward(
+            mut from: tokio::net::tcp::OwnedReadHalf,
+            mut to: tokio::net::tcp::OwnedWriteHalf,
+            dropped: Arc<AtomicBool>,
+        ) {
+            let mut buf = [0u8; 4096];
+            loop {
+                match from.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) if dropped.load(Ordering::SeqCst) => (),
+                    Ok(n) => {
+                        if to.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        pub(crate) fn blackhole(&self) {
+            for dropped in self.connections.lock().expect("proxy connections").iter() {
+                dropped.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    impl Drop for BlackholeProxy {
+        fn drop(&mut self) {
+            self.task.abort();
+        }
+    }
+}
@@ -1,8 +1,16 @@
-use crate::{api::GatewayStakingMode, KeyedUri, Keypair, PublicKey, Region, Result};
+use crate::{
+    api::GatewayStakingMode, service::conduit::KeepAlive, Error, KeyedUri, Keypair, PublicKey,
+    Region, Result,
+};
 use config::{Config, Environment, File};
 use http::uri::Uri;
 use serde::Deserialize;
-use std::{fmt, path::Path, str::FromStr, sync::Arc};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
 
 pub fn version() -> semver::Version {
     semver::Version::parse(env!("CARGO_PKG_VERSION")).expect("unable to parse version")
@@ -32,6 +40,11 @@ pub struct Settings {
     /// asserted location/region is fetched.
     #[serde(default)]
     pub region: Region,
+    /// The directory to cache state in across restarts, like the last fetched
+    /// region parameters. Caching is disabled when not set. Startup fails if
+    /// the directory is set but can not be created or written.
+    #[serde(default)]
+    pub cache: Option<PathBuf>,
     /// Log settings
     pub log: LogSettings,
     /// The config service to use for region and other config settings
@@ -40,6 +53,9 @@ pub struct Settings {
     pub router: RouterSettings,
     /// Proof-of-coverage (PoC) settings.
     pub poc: PocSettings,
+    /// Watchdog settings for the service tasks
+    #[serde(default)]
+    pub watchdog: WatchdogSettings,
 }
 
 /// Settings for log method and level to be used by the running service.
@@ -50,9 +66,31 @@ pub struct LogSettings {
 
     /// Whehter to show timestamps in the stdio output stream (default false)
     pub timestamp: bool,
+
+    /// The directory to write a daily rolling audit log of forwarded uplinks,
+    /// transmitted downlinks and beacons to. Audit logging is disabled when
+    /// not set. Startup fails if the directory is set but can not be created
+    /// or written.
+    #[serde(default)]
+    pub audit: Option<PathBuf>,
 }
 
 impl LogSettings {
+    /// Loads just the log settings from the given settings file path and
+    /// environment overrides. This is used to reload the log settings of a
+    /// running service without re-reading the rest of the settings (like the
+    /// keypair).
+    pub fn new(path: &Path) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct LogOnly {
+            log: LogSettings,
+        }
+        mk_config(path)
+            .and_then(|config| config.try_deserialize::<LogOnly>())
+            .map(|settings| settings.log)
+            .map_err(|e| e.into())
+    }
+
     pub fn time_formatter(&self) -> impl tracing_subscriber::fmt::time::FormatTime {
         TimeFormatter {
             timestamp: self.timestamp,
@@ -95,6 +133,28 @@ pub struct PocSettings {
     pub interval: u64,
 }
 
+/// Settings for the watchdog on the service tasks.
+#[derive(Debug, Deserialize, Clone)]
+pub struct WatchdogSettings {
+    /// Time in seconds a task may go without a heartbeat before it is
+    /// considered stalled. Defaults to 300 seconds, 0 disables the watchdog.
+    #[serde(default = "default_watchdog_timeout")]
+    pub timeout: u64,
+    /// Whether the service exits when a task stalls, so a service manager can
+    /// restart it. Defaults to false, which only logs stalled tasks.
+    #[serde(default)]
+    pub exit: bool,
+}
+
+impl Default for WatchdogSettings {
+    fn default() -> Self {
+        Self {
+            timeout: default_watchdog_timeout(),
+            exit: false,
+        }
+    }
+}
+
 /// Settings for packet routing
 #[derive(Debug, Deserialize, Clone)]
 pub struct RouterSettings {
@@ -102,6 +162,74 @@ pub struct RouterSettings {
     pub uri: Uri,
     // Maximum number of packets to queue up for the packet router
     pub queue: u16,
+    /// Interval in seconds between HTTP/2 keepalive pings sent to the packet
+    /// router. Default 60, 0 disables keepalive pings
+    #[serde(default = "default_router_keepalive_interval")]
+    pub keepalive_interval: u64,
+    /// Time in seconds to wait for a keepalive ping to be acknowledged before
+    /// the packet router connection is considered dead and reconnected.
+    /// Default 20, 0 disables keepalive pings
+    #[serde(default = "default_router_keepalive_timeout")]
+    pub keepalive_timeout: u64,
+    /// Additional packet routers to connect to besides the one in uri
+    #[serde(default)]
+    pub routers: Vec<RouterUri>,
+    /// How uplinks are sent when more than one packet router is configured.
+    /// Default failover
+    #[serde(default)]
+    pub mode: RouterMode,
+}
+
+/// An additional packet router
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouterUri {
+    #[serde(with = "http_serde::uri")]
+    pub uri: Uri,
+    /// Priority of the router where lower values are preferred. The router in
+    /// the uri of the router settings has priority 0. Default 1
+    #[serde(default = "default_router_priority")]
+    pub priority: u32,
+}
+
+/// How uplinks are sent when more than one packet router is configured
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RouterMode {
+    /// Uplinks are sent to the connected router with the highest priority,
+    /// and queued for the router in uri when no router is connected
+    #[default]
+    Failover,
+    /// Uplinks are sent to every router
+    Mirror,
+}
+
+impl RouterSettings {
+    /// Returns the uris of all configured packet routers ordered by priority.
+    /// The router in uri comes first among routers of the same priority.
+    pub fn uris(&self) -> Vec<Uri> {
+        let mut routers = vec![(0, &self.uri)];
+        routers.extend(
+            self.routers
+                .iter()
+                .map(|router| (router.priority, &router.uri)),
+        );
+        // A stable sort keeps the configured order for equal priorities
+        routers.sort_by_key(|(priority, _)| *priority);
+        routers.into_iter().map(|(_, uri)| uri.clone()).collect()
+    }
+
+    /// Returns the keepalive configuration for the packet router connection.
+    /// Setting either the interval or the timeout to 0 disables keepalive
+    /// pings.
+    pub fn keepalive(&self) -> Option<KeepAlive> {
+        if self.keepalive_interval == 0 || self.keepalive_timeout == 0 {
+            return None;
+        }
+        Some(KeepAlive {
+            interval: std::time::Duration::from_secs(self.keepalive_interval),
+            timeout: std::time::Duration::from_secs(self.keepalive_timeout),
+        })
+    }
 }
 
 impl Settings {
@@ -111,13 +239,7 @@ impl Settings {
     /// file in uppercase and prefixed with "GW_". For example "GW_KEY" will
     /// override the key file location.
     pub fn new(path: &Path) -> Result<Self> {
-        Config::builder()
-            // Source settings file
-            .add_source(File::with_name(path.to_str().expect("file name")).required(false))
-            // Add in settings from the environment (with a prefix of APP)
-            // Eg.. `GW_DEBUG=1 ./target/app` would set the `debug` key
-            .add_source(Environment::with_prefix("gw").separator("_"))
-            .build()
+        mk_config(path)
             .and_then(|config| config.try_deserialize())
             .map_err(|e| e.into())
     }
@@ -136,6 +258,38 @@ impl Settings {
             },
         )
     }
+
+    /// Creates the configured cache directory if it does not exist yet and
+    /// checks that it is writable. This is a no-op if caching is disabled.
+    /// This is meant to be called once on startup so that a misconfigured
+    /// cache directory is reported right away.
+    pub fn init_cache_dir(&self) -> Result {
+        self.cache
+            .as_deref()
+            .map_or(Ok(()), |dir| init_writable_dir("cache", dir))
+    }
+}
+
+/// Creates the given directory if needed and checks that files can be written
+/// to it. The returned error names the setting and directory involved.
+pub(crate) fn init_writable_dir(name: &str, dir: &Path) -> Result {
+    let check_dir = || -> std::io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let probe = dir.join(".write_check");
+        fs::write(&probe, b"")?;
+        fs::remove_file(&probe)
+    };
+    check_dir().map_err(|err| Error::custom(format!("{name} dir {}: {err}", dir.display())))
+}
+
+fn mk_config(path: &Path) -> std::result::Result<Config, config::ConfigError> {
+    Config::builder()
+        // Source settings file
+        .add_source(File::with_name(path.to_str().expect("file name")).required(false))
+        // Add in settings from the environment (with a prefix of APP)
+        // Eg.. `GW_DEBUG=1 ./target/app` would set the `debug` key
+        .add_source(Environment::with_prefix("gw").separator("_"))
+        .build()
 }
 
 fn default_listen() -> String {
@@ -151,6 +305,22 @@ fn default_poc_interval() -> u64 {
     6 * 3600
 }
 
+fn default_router_keepalive_interval() -> u64 {
+    60
+}
+
+fn default_router_keepalive_timeout() -> u64 {
+    20
+}
+
+fn default_router_priority() -> u32 {
+    1
+}
+
+fn default_watchdog_timeout() -> u64 {
+    300
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Copy, clap::ValueEnum)]
 #[clap(rename_all = "lower")]
 #[repr(u8)]
@@ -293,6 +463,116 @@ mod test {
     use super::*;
     use std::net::SocketAddr;
 
+    #[test]
+    fn router_keepalive() {
+        let mut settings = RouterSettings {
+            uri: Uri::from_static("http://127.0.0.1:8080"),
+            queue: 20,
+            keepalive_interval: 60,
+            keepalive_timeout: 20,
+            routers: vec![],
+            mode: RouterMode::default(),
+        };
+        let keepalive = settings.keepalive().expect("keepalive");
+        assert_eq!(std::time::Duration::from_secs(60), keepalive.interval);
+        assert_eq!(std::time::Duration::from_secs(20), keepalive.timeout);
+
+        settings.keepalive_timeout = 0;
+        assert!(settings.keepalive().is_none());
+        settings.keepalive_timeout = 20;
+        settings.keepalive_interval = 0;
+        assert!(settings.keepalive().is_none());
+    }
+
+    #[test]
+    fn ipv6_router_uri() {
+        let router: RouterSettings = serde_json::from_value(serde_json::json!({
+            "uri": "http://[::1]:8080",
+            "queue": 20,
+        }))
+        .expect("router settings with ipv6 uri");
+        assert_eq!(Uri::from_static("http://[::1]:8080"), router.uri);
+        assert_eq!(Some("[::1]"), router.uri.host());
+        assert_eq!(Some(8080), router.uri.port_u16());
+    }
+
+    #[test]
+    fn router_uris() {
+        let router: RouterSettings = serde_json::from_value(serde_json::json!({
+            "uri": "http://127.0.0.1:8080",
+            "queue": 20,
+        }))
+        .expect("router settings");
+        assert_eq!(RouterMode::Failover, router.mode);
+        assert_eq!(
+            vec![Uri::from_static("http://127.0.0.1:8080")],
+            router.uris()
+        );
+
+        let router: RouterSettings = serde_json::from_value(serde_json::json!({
+            "uri": "http://127.0.0.1:8080",
+            "queue": 20,
+            "mode": "mirror",
+            "routers": [
+                { "uri": "http://127.0.0.1:8082", "priority": 2 },
+                { "uri": "http://127.0.0.1:8081" },
+                { "uri": "http://127.0.0.1:8079", "priority": 0 },
+            ],
+        }))
+        .expect("router settings");
+        assert_eq!(RouterMode::Mirror, router.mode);
+        assert_eq!(
+            vec![
+                Uri::from_static("http://127.0.0.1:8080"),
+                Uri::from_static("http://127.0.0.1:8079"),
+                Uri::from_static("http://127.0.0.1:8081"),
+                Uri::from_static("http://127.0.0.1:8082"),
+            ],
+            router.uris()
+        );
+    }
+
+    #[test]
+    fn cache_dir() {
+        let base = std::env::temp_dir().join(format!("cache_dir_{}", std::process::id()));
+
+        // Missing directories are created
+        let dir = base.join("missing").join("cache");
+        init_writable_dir("cache", &dir).expect("created cache dir");
+        assert!(dir.is_dir());
+        // Existing directories are accepted
+        init_writable_dir("cache", &dir).expect("existing cache dir");
+
+        // A directory that can't be created is rejected
+        let file = base.join("file");
+        fs::write(&file, b"").expect("file");
+        assert!(init_writable_dir("cache", &file.join("cache")).is_err());
+
+        let _ = fs::remove_dir_all(&base);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cache_dir_read_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("cache_dir_ro_{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("cache dir");
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).expect("read only");
+
+        // Permissions are not enforced for a privileged user, in which case
+        // there is no unwritable directory to test with
+        let probe = dir.join("probe");
+        if fs::write(&probe, b"").is_ok() {
+            let _ = fs::remove_file(&probe);
+        } else {
+            let err = init_writable_dir("cache", &dir).expect_err("read only cache dir");
+            assert!(err.to_string().contains(&dir.display().to_string()));
+        }
+
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).expect("writable");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn listen_addr() {
         assert_eq!(
@@ -309,6 +589,11 @@ mod test {
                 .expect("socket addr from addr string"),
             "1.2.3.4:4468".parse().expect("socket addr")
         );
+        assert_eq!(
+            SocketAddr::try_from(&ListenAddress::Address("[::1]:4468".to_string()))
+                .expect("socket addr from ipv6 addr string"),
+            "[::1]:4468".parse().expect("socket addr")
+        );
 
         // Now try URI form
         assert_eq!(
@@ -324,5 +609,10 @@ mod test {
                 .expect("uri from addr string"),
             Uri::from_static("http://1.2.3.4:4468")
         );
+        assert_eq!(
+            Uri::try_from(&ListenAddress::Address("[::1]:4468".to_string()))
+                .expect("uri from ipv6 addr string"),
+            Uri::from_static("http://[::1]:4468")
+        );
     }
 }
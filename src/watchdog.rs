@@ -0,0 +1,200 @@
+//! A watchdog for the long running tasks of the gateway service.
+//!
+//! Each watched task is handed a [`Heartbeat`] which it ticks from its run
+//! loop. The [`Watchdog`] logs a task that has not beaten for the configured
+//! timeout and, when configured to exit, fails so the service exits and can be
+//! restarted by its service manager.
+use crate::{settings::WatchdogSettings, Error, Result};
+use std::time::Duration;
+use tokio::{
+    sync::watch,
+    time::{self, Instant, MissedTickBehavior},
+};
+use tracing::{info, warn};
+
+/// The number of heartbeats and watchdog checks per timeout period
+const CHECKS_PER_TIMEOUT: u32 = 4;
+
+/// A heartbeat for a watched task. Tasks select on [`Heartbeat::tick`] in
+/// their run loop so a stalled loop stops beating. The default heartbeat is
+/// not watched and never ticks.
+#[derive(Debug, Default)]
+pub struct Heartbeat(Option<(time::Interval, watch::Sender<Instant>)>);
+
+impl Heartbeat {
+    /// Waits for the next heartbeat interval and records the beat. Stays
+    /// pending forever for an unwatched heartbeat.
+    pub async fn tick(&mut self) {
+        match self.0.as_mut() {
+            Some((interval, beat)) => {
+                interval.tick().await;
+                beat.send_replace(Instant::now());
+            }
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+struct WatchedTask {
+    name: &'static str,
+    beat: watch::Receiver<Instant>,
+    stalled: bool,
+}
+
+pub struct Watchdog {
+    timeout: Duration,
+    exit: bool,
+    tasks: Vec<WatchedTask>,
+}
+
+impl Watchdog {
+    pub fn new(settings: &WatchdogSettings) -> Self {
+        Self {
+            timeout: Duration::from_secs(settings.timeout),
+            exit: settings.exit,
+            tasks: vec![],
+        }
+    }
+
+    /// Returns a heartbeat for the named task to tick. The heartbeat is not
+    /// watched when the watchdog is disabled.
+    pub fn heartbeat(&mut self, name: &'static str) -> Heartbeat {
+        if self.timeout.is_zero() {
+            return Heartbeat::default();
+        }
+        let (beat_tx, beat_rx) = watch::channel(Instant::now());
+        let mut interval = time::interval(self.check_period());
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.tasks.push(WatchedTask {
+            name,
+            beat: beat_rx,
+            stalled: false,
+        });
+        Heartbeat(Some((interval, beat_tx)))
+    }
+
+    fn check_period(&self) -> Duration {
+        self.timeout / CHECKS_PER_TIMEOUT
+    }
+
+    pub async fn run(&mut self, shutdown: &triggered::Listener) -> Result {
+        if self.timeout.is_zero() {
+            info!("disabled");
+            shutdown.clone().await;
+            return Ok(());
+        }
+        info!(
+            timeout = self.timeout.as_secs(),
+            exit = self.exit,
+            tasks = self.tasks.len(),
+            "starting"
+        );
+
+        let mut check = time::interval(self.check_period());
+        check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
+                    info!("shutting down");
+                    return Ok(())
+                },
+                _ = check.tick() => self.check_tasks()?,
+            }
+        }
+    }
+
+    fn check_tasks(&mut self) -> Result {
+        let now = Instant::now();
+        for task in self.tasks.iter_mut() {
+            let silent = now.duration_since(*task.beat.borrow());
+            match (silent > self.timeout, task.stalled) {
+                (true, false) => {
+                    warn!(task = task.name, silent = silent.as_secs(), "task stalled");
+                    task.stalled = true;
+                    if self.exit {
+                        return Err(Error::custom(format!(
+                            "{} task stalled for {}s",
+                            task.name,
+                            silent.as_secs()
+                        )));
+                    }
+                }
+                (false, true) => {
+                    info!(task = task.name, "task recovered");
+                    task.stalled = false;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mk_watchdog(exit: bool) -> Watchdog {
+        Watchdog::new(&WatchdogSettings { timeout: 60, exit })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_task() {
+        let mut watchdog = mk_watchdog(true);
+        let mut alive = watchdog.heartbeat("gateway");
+        let mut stalled = watchdog.heartbeat("router");
+        let (_trigger, shutdown) = triggered::trigger();
+
+        // The stalled task beats once and then stops ticking its heartbeat
+        stalled.tick().await;
+        let started = Instant::now();
+        let result = tokio::select! {
+            result = watchdog.run(&shutdown) => result,
+            _ = async { loop { alive.tick().await } } => unreachable!(),
+        };
+        let elapsed = started.elapsed();
+
+        let err = result.expect_err("watchdog tripped").to_string();
+        assert!(err.contains("router task stalled"), "{err}");
+        assert!(elapsed > watchdog.timeout);
+        assert!(elapsed <= watchdog.timeout + watchdog.check_period());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_healthy_tasks() {
+        let mut watchdog = mk_watchdog(true);
+        let mut first = watchdog.heartbeat("first");
+        let mut second = watchdog.heartbeat("second");
+        let (trigger, shutdown) = triggered::trigger();
+
+        let run_until = Instant::now() + watchdog.timeout * 10;
+        let tasks = async {
+            loop {
+                tokio::select! {
+                    _ = first.tick() => (),
+                    _ = second.tick() => (),
+                    _ = time::sleep_until(run_until) => break,
+                }
+            }
+            trigger.trigger();
+        };
+        let (result, _) = tokio::join!(watchdog.run(&shutdown), tasks);
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_task_no_exit() {
+        let mut watchdog = mk_watchdog(false);
+        let _stalled = watchdog.heartbeat("stalled");
+        let (trigger, shutdown) = triggered::trigger();
+
+        let run_for = watchdog.timeout * 2;
+        let stop = async {
+            time::sleep(run_for).await;
+            trigger.trigger();
+        };
+        let (result, _) = tokio::join!(watchdog.run(&shutdown), stop);
+        assert!(result.is_ok());
+        assert!(watchdog.tasks[0].stalled);
+    }
+}